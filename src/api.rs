@@ -0,0 +1,185 @@
+use alloc::vec::Vec;
+use embassy_executor::task;
+use embassy_net::{Stack, tcp::TcpSocket};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Sender};
+use embassy_time::{Duration, Timer};
+use serde::de::DeserializeOwned;
+
+use crate::config::{Config, EffectConfig, SharedConfig};
+use crate::http::{self, Request};
+use crate::led::{self, Command, Light, PresetCall};
+use crate::mdns::HTTP_PORT;
+use crate::ota::{self, Manifest};
+use crate::version;
+use crate::watchdog::{self, Watched};
+use crate::ws;
+use crate::{error, info, warn};
+
+const JSON: &str = "application/json";
+const CBOR: &str = "application/cbor";
+
+/// Local REST control, usable while the broker is unreachable. Several
+/// instances listen on the port so that a WebSocket client does not lock the
+/// API out.
+#[task(pool_size = 2)]
+pub async fn api_task(
+    stack: Stack<'static>,
+    commands: &'static Sender<'static, NoopRawMutex, Command, 3>,
+    config: &'static SharedConfig,
+) {
+    let mut rx_buffer = [0u8; 2048];
+    let mut tx_buffer = [0u8; 2048];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        watchdog::check_in(Watched::Api);
+        if let Err(e) = watchdog::idle(Watched::Api, socket.accept(HTTP_PORT)).await {
+            error!("accept error: {:?}", e);
+            continue;
+        }
+
+        match http::read_request(&mut socket).await {
+            Ok(request) if request.path == "/ws" && ws::is_upgrade(&request) => {
+                ws::serve(&mut socket, &request, commands).await;
+            }
+            Ok(request) => {
+                let (status, content_type, body) = handle(&request, commands, config).await;
+                http::respond(&mut socket, status, content_type, &body)
+                    .await
+                    .ok();
+                if status == "200 OK" && request.path == "/reboot" {
+                    socket.close();
+                    info!("Rebooting on request");
+                    Timer::after(Duration::from_secs(1)).await;
+                    esp_hal::system::software_reset();
+                }
+            }
+            Err(e) => warn!("bad request: {:?}", e),
+        }
+        socket.close();
+        Timer::after(Duration::from_millis(50)).await;
+        socket.abort();
+    }
+}
+
+async fn handle(
+    request: &Request,
+    commands: &Sender<'static, NoopRawMutex, Command, 3>,
+    config: &SharedConfig,
+) -> (&'static str, &'static str, Vec<u8>) {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => ("200 OK", "text/html", ws::PAGE.into()),
+        ("GET", "/state") => {
+            let mut buf = [0u8; 128];
+            let n = serde_json_core::to_slice(&led::state(), &mut buf).unwrap();
+            ("200 OK", JSON, buf[..n].into())
+        }
+        ("GET", "/version") => {
+            let mut buf = [0u8; 128];
+            let n = serde_json_core::to_slice(&version::BUILD, &mut buf).unwrap();
+            ("200 OK", JSON, buf[..n].into())
+        }
+        ("POST", "/light") => match decode::<Light>(request) {
+            Some(light) => {
+                commands.send(Command::Light(light)).await;
+                no_content()
+            }
+            None => bad_request(),
+        },
+        ("POST", "/effect") => match decode::<EffectConfig>(request) {
+            Some(effect) => {
+                commands.send(Command::Effect(effect)).await;
+                no_content()
+            }
+            None => bad_request(),
+        },
+        ("POST", "/preset") => match decode::<PresetCall>(request) {
+            Some(call) => {
+                commands.send(call.into()).await;
+                no_content()
+            }
+            None => bad_request(),
+        },
+        // The image is fetched from the manifest's URL in the background.
+        ("POST", "/ota") => match decode::<Manifest>(request) {
+            Some(manifest) if manifest.url.is_some() => {
+                ota::FETCH.signal(manifest);
+                ("202 Accepted", JSON, Vec::new())
+            }
+            _ => bad_request(),
+        },
+        ("GET", "/config") => {
+            let mut config = config.lock().await.get().clone();
+            // Stored passwords never leave the device.
+            for network in &mut config.wifi.networks {
+                network.password.clear();
+            }
+            ("200 OK", CBOR, minicbor::to_vec(&config).unwrap())
+        }
+        ("PUT", "/config") => {
+            let Ok(mut new) = minicbor::decode::<Config>(&request.body) else {
+                return bad_request();
+            };
+            if let Err(e) = new.validate() {
+                warn!("config rejected: {:?}", e);
+                return bad_request();
+            }
+            let res = config.lock().await.update(|c| {
+                keep_passwords(&mut new, c);
+                // The rollback counter only ever moves forward.
+                new.ota_version = new.ota_version.max(c.ota_version);
+                *c = new;
+            });
+            match res {
+                Ok(_) => no_content(),
+                Err(e) => {
+                    error!("failed to save config: {:?}", e);
+                    ("500 Internal Server Error", JSON, Vec::new())
+                }
+            }
+        }
+        ("POST", "/reboot") => ("200 OK", JSON, Vec::new()),
+        (
+            _,
+            "/state" | "/version" | "/light" | "/effect" | "/preset" | "/ota" | "/config"
+            | "/reboot",
+        ) => ("405 Method Not Allowed", JSON, Vec::new()),
+        _ => ("404 Not Found", JSON, Vec::new()),
+    }
+}
+
+/// Decodes a CBOR or JSON body depending on its `Content-Type`.
+fn decode<T>(request: &Request) -> Option<T>
+where
+    T: for<'b> minicbor::Decode<'b, ()> + DeserializeOwned,
+{
+    if request.is_cbor() {
+        minicbor::decode(&request.body).ok()
+    } else {
+        serde_json_core::from_slice(&request.body)
+            .ok()
+            .map(|(v, _)| v)
+    }
+}
+
+/// An empty password in an uploaded config keeps the stored one, so that what
+/// `GET /config` returns can be edited and put back.
+fn keep_passwords(new: &mut Config, old: &Config) {
+    for network in &mut new.wifi.networks {
+        if network.password.is_empty()
+            && let Some(stored) = old.wifi.networks.iter().find(|n| n.ssid == network.ssid)
+        {
+            network.password = stored.password.clone();
+        }
+    }
+}
+
+fn no_content() -> (&'static str, &'static str, Vec<u8>) {
+    ("204 No Content", JSON, Vec::new())
+}
+
+fn bad_request() -> (&'static str, &'static str, Vec<u8>) {
+    ("400 Bad Request", JSON, Vec::new())
+}
@@ -0,0 +1,39 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Instant;
+
+use crate::config::TimeConfig;
+
+pub use wink_core::clock::*;
+
+// Unix time in microseconds at a local instant, once SNTP has answered.
+static BASE: Mutex<CriticalSectionRawMutex, Cell<Option<(Instant, u64)>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn set_utc(at: Instant, unix_micros: u64) {
+    BASE.lock(|b| b.set(Some((at, unix_micros))));
+}
+
+/// Microseconds since the Unix epoch, if the clock was ever set.
+pub fn utc_micros() -> Option<u64> {
+    let (at, base) = BASE.lock(|b| b.get())?;
+    Some(base + Instant::now().saturating_duration_since(at).as_micros())
+}
+
+/// Microseconds to add to `Instant::now()` to get Unix time, if the clock was
+/// ever set.
+pub fn offset_micros() -> Option<i64> {
+    let (at, base) = BASE.lock(|b| b.get())?;
+    Some(base as i64 - at.as_micros() as i64)
+}
+
+/// Seconds since the Unix epoch, if the clock was ever set.
+pub fn utc() -> Option<u64> {
+    utc_micros().map(|t| t / 1_000_000)
+}
+
+/// Local civil time according to the configured zone.
+pub fn local(zone: &TimeConfig) -> Option<DateTime> {
+    Some(DateTime::from_unix(to_local(zone, utc()? as i64)))
+}
@@ -0,0 +1,70 @@
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embedded_storage::nor_flash::NorFlash;
+
+pub use wink_core::config::*;
+
+use crate::flash::Partition;
+use crate::{info, warn};
+
+pub type SharedConfig = Mutex<NoopRawMutex, ConfigStore<Partition>>;
+
+/// Keeps the configuration in a flash partition and a copy of it in RAM.
+pub struct ConfigStore<F> {
+    store: Store<F>,
+    config: Config,
+}
+
+impl<F: NorFlash> ConfigStore<F> {
+    pub fn open(flash: F) -> Self {
+        let mut store = Store::new(flash);
+        let loaded = match store.load() {
+            Ok(Some((version, payload))) => match migrate(version, &payload) {
+                Some(config) => Some((version, config)),
+                None => {
+                    warn!("config schema {} not understood, using defaults", version);
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(_) => {
+                warn!("config partition unreadable, using defaults");
+                None
+            }
+        };
+
+        let mut cs = ConfigStore {
+            store,
+            config: Config::default(),
+        };
+        match loaded {
+            Some((version, config)) => {
+                if version != SCHEMA_VERSION {
+                    info!("migrated config from schema {}", version);
+                    cs.save(&config).ok();
+                }
+                cs.config = config;
+            }
+            None => info!("no stored config"),
+        }
+        cs
+    }
+
+    pub fn get(&self) -> &Config {
+        &self.config
+    }
+
+    /// Applies `f` to a copy of the configuration, which only replaces the
+    /// one in RAM once it has been saved.
+    pub fn update(&mut self, f: impl FnOnce(&mut Config)) -> Result<(), StoreError> {
+        let mut config = self.config.clone();
+        f(&mut config);
+        self.save(&config)?;
+        self.config = config;
+        Ok(())
+    }
+
+    fn save(&mut self, config: &Config) -> Result<(), StoreError> {
+        let payload = minicbor::to_vec(config).map_err(|_| StoreError::Encode)?;
+        self.store.save(SCHEMA_VERSION, &payload)
+    }
+}
@@ -0,0 +1,153 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use defmt::{Display2Format, error, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use esp_backtrace::Backtrace;
+use minicbor::Encode;
+
+use crate::log;
+use crate::version;
+
+const MAGIC: u32 = 0x4352_5348;
+const MESSAGE_LEN: usize = 192;
+const FRAMES: usize = 10;
+const GIT_LEN: usize = 16;
+
+// Written by the panic handler just before it resets, and survives the reset
+// but not a power cycle.
+#[repr(C)]
+struct Record {
+    magic: u32,
+    message_len: u32,
+    message: [u8; MESSAGE_LEN],
+    frames_len: u32,
+    frames: [u32; FRAMES],
+    git: [u8; GIT_LEN],
+}
+
+// SAFETY: only integers and arrays of them, for which any bit pattern is valid.
+unsafe impl esp_hal::Persistable for Record {}
+
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut RECORD: Record = Record {
+    magic: 0,
+    message_len: 0,
+    message: [0; MESSAGE_LEN],
+    frames_len: 0,
+    frames: [0; FRAMES],
+    git: [0; GIT_LEN],
+};
+
+/// What is known about the panic that caused the last reset.
+#[derive(Clone, Encode)]
+#[cbor(map)]
+pub struct Crash {
+    #[n(0)]
+    pub message: String,
+    // Where the panic was raised from, the first of `backtrace`.
+    #[n(1)]
+    pub pc: Option<u32>,
+    #[n(2)]
+    pub backtrace: Vec<u32>,
+    // Reset reason code of the chip.
+    #[n(3)]
+    pub reset: Option<u8>,
+    // Git hash of the build that crashed, which the addresses belong to.
+    #[n(4)]
+    pub git: String,
+}
+
+// Kept until it has been published.
+static PENDING: Mutex<CriticalSectionRawMutex, RefCell<Option<Crash>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Takes over the record left by a panic before the reset, if any.
+pub fn init() {
+    let record = unsafe { &mut *addr_of_mut!(RECORD) };
+    if record.magic != MAGIC {
+        return;
+    }
+    record.magic = 0;
+    let message = &record.message[..(record.message_len as usize).min(MESSAGE_LEN)];
+    let frames = &record.frames[..(record.frames_len as usize).min(FRAMES)];
+    let git = record.git.split(|b| *b == 0).next().unwrap_or_default();
+    let crash = Crash {
+        message: String::from_utf8_lossy(message).into(),
+        pc: frames.first().copied(),
+        backtrace: frames.to_vec(),
+        reset: esp_hal::system::reset_reason().map(|r| r as u8),
+        git: String::from_utf8_lossy(git).into(),
+    };
+    warn!("Reset after a panic: {}", crash.message.as_str());
+    PENDING.lock(|p| p.replace(Some(crash)));
+}
+
+/// The crash not yet published, if there is one.
+pub fn pending() -> Option<Crash> {
+    PENDING.lock(|p| p.borrow().clone())
+}
+
+/// Marks the pending crash as published.
+pub fn reported() {
+    PENDING.lock(|p| p.take());
+}
+
+// Formats into a fixed buffer, dropping whatever does not fit.
+struct Truncated<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncated<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let backtrace = Backtrace::capture();
+
+    // Written first, so that the record is kept even if logging fails. Only
+    // `init` touches it otherwise, long before any panic.
+    let record = unsafe { &mut *addr_of_mut!(RECORD) };
+    let mut message = Truncated {
+        buf: &mut record.message,
+        len: 0,
+    };
+    let _ = write!(message, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = write!(message, " at {}:{}", location.file(), location.line());
+    }
+    record.message_len = message.len as u32;
+    let frames = backtrace.frames();
+    for (slot, frame) in record.frames.iter_mut().zip(frames) {
+        *slot = frame.program_counter() as u32;
+    }
+    record.frames_len = frames.len().min(FRAMES) as u32;
+    let git = version::BUILD.git.as_bytes();
+    let n = git.len().min(GIT_LEN);
+    record.git = [0; GIT_LEN];
+    record.git[..n].copy_from_slice(&git[..n]);
+    record.magic = MAGIC;
+
+    // A panic raised while a record was being logged cannot log itself.
+    if !log::is_taken() {
+        error!("====================== PANIC ======================");
+        error!("{}", Display2Format(info));
+        error!("Backtrace:");
+        for frame in backtrace.frames() {
+            error!("0x{:x}", frame.program_counter());
+        }
+    }
+
+    esp_hal::system::software_reset()
+}
@@ -0,0 +1,289 @@
+use core::net::Ipv4Addr;
+
+use alloc::vec::Vec;
+use embassy_executor::task;
+use embassy_futures::select::{Either, select};
+use embassy_net::{
+    IpAddress, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Instant};
+use smart_leds_trait::RGB8;
+
+use crate::config::{RealtimeConfig, SharedConfig};
+use crate::led::{LEDS, REALTIME, Realtime};
+use crate::watchdog::{self, Watched};
+use crate::{error, info};
+
+const E131_PORT: u16 = 5568;
+const ARTNET_PORT: u16 = 6454;
+
+const ACN_ID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+const OPTION_PREVIEW: u8 = 0x80;
+const OPTION_TERMINATED: u8 = 0x40;
+
+const ARTNET_ID: &[u8; 8] = b"Art-Net\0";
+const OP_DMX: u16 = 0x5000;
+// Art-Net has no notion of priority; it ranks with the E1.31 default.
+const ARTNET_PRIORITY: u8 = 100;
+
+const CHANNELS: usize = 512;
+const PIXELS_PER_UNIVERSE: usize = CHANNELS / 3;
+const MAX_SEQUENCES: usize = 16;
+
+/// One DMX universe worth of data, whichever protocol carried it.
+struct Dmx<'a> {
+    source: [u8; 16],
+    priority: u8,
+    // None when the sender does not number its packets.
+    sequence: Option<u8>,
+    universe: u16,
+    // The stream is about to end; E1.31 senders say so explicitly.
+    terminated: bool,
+    data: &'a [u8],
+}
+
+/// Receives E1.31 and Art-Net DMX and hands the pixels to the renderer.
+#[task]
+pub async fn dmx_task(stack: Stack<'static>, config: &'static SharedConfig) {
+    let (realtime, leds) = {
+        let config = config.lock().await;
+        (
+            config.get().realtime.clone(),
+            (config.get().strip.leds as usize).min(LEDS),
+        )
+    };
+    let mut mapper = Mapper::new(&realtime, leds);
+
+    watchdog::idle(Watched::Dmx, stack.wait_config_up()).await;
+    // E1.31 senders multicast each universe to its own group.
+    for universe in mapper.universes() {
+        let [hi, lo] = universe.to_be_bytes();
+        if let Err(e) = stack.join_multicast_group(Ipv4Addr::new(239, 255, hi, lo)) {
+            error!("sACN join error for universe {}: {:?}", universe, e);
+        }
+    }
+
+    let mut e131_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut e131_rx_buffer = [0u8; 2048];
+    let mut e131_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut e131_tx_buffer = [0u8; 16];
+    let mut e131 = UdpSocket::new(
+        stack,
+        &mut e131_rx_meta,
+        &mut e131_rx_buffer,
+        &mut e131_tx_meta,
+        &mut e131_tx_buffer,
+    );
+    let mut artnet_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut artnet_rx_buffer = [0u8; 2048];
+    let mut artnet_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut artnet_tx_buffer = [0u8; 16];
+    let mut artnet = UdpSocket::new(
+        stack,
+        &mut artnet_rx_meta,
+        &mut artnet_rx_buffer,
+        &mut artnet_tx_meta,
+        &mut artnet_tx_buffer,
+    );
+    if let Err(e) = e131.bind(E131_PORT) {
+        error!("sACN bind error: {:?}", e);
+        watchdog::check_out(Watched::Dmx);
+        return;
+    }
+    if let Err(e) = artnet.bind(ARTNET_PORT) {
+        error!("Art-Net bind error: {:?}", e);
+        watchdog::check_out(Watched::Dmx);
+        return;
+    }
+    info!(
+        "DMX: universe {} channel {} for {} pixels",
+        realtime.universe, realtime.channel, leds
+    );
+
+    let mut e131_buf = [0u8; 638];
+    let mut artnet_buf = [0u8; 530];
+    loop {
+        let received = select(
+            e131.recv_from(&mut e131_buf),
+            artnet.recv_from(&mut artnet_buf),
+        );
+        let dmx = match watchdog::idle(Watched::Dmx, received).await {
+            Either::First(Ok((n, _))) => parse_e131(&e131_buf[..n]),
+            Either::Second(Ok((n, meta))) => {
+                let IpAddress::Ipv4(ip) = meta.endpoint.addr;
+                parse_artnet(&artnet_buf[..n], ip)
+            }
+            _ => continue,
+        };
+        if let Some(dmx) = dmx
+            && mapper.accept(&dmx)
+        {
+            REALTIME.signal(Realtime {
+                pixels: mapper.frame.clone(),
+                timeout: mapper.timeout,
+            });
+        }
+    }
+}
+
+fn parse_e131(packet: &[u8]) -> Option<Dmx<'_>> {
+    let u32_at = |i: usize| Some(u32::from_be_bytes(packet.get(i..i + 4)?.try_into().ok()?));
+    let u16_at = |i: usize| Some(u16::from_be_bytes(packet.get(i..i + 2)?.try_into().ok()?));
+
+    if packet.get(4..16)? != ACN_ID
+        || u32_at(18)? != VECTOR_ROOT_E131_DATA
+        || u32_at(40)? != VECTOR_E131_DATA_PACKET
+    {
+        return None;
+    }
+    let options = *packet.get(112)?;
+    if options & OPTION_PREVIEW != 0 {
+        return None;
+    }
+    // Property values start with the DMX start code, which must be zero.
+    let count = u16_at(123)? as usize;
+    if count == 0 || *packet.get(125)? != 0 {
+        return None;
+    }
+    Some(Dmx {
+        source: packet[22..38].try_into().ok()?,
+        priority: *packet.get(108)?,
+        sequence: Some(*packet.get(111)?),
+        universe: u16_at(113)?,
+        terminated: options & OPTION_TERMINATED != 0,
+        data: packet.get(126..125 + count)?,
+    })
+}
+
+fn parse_artnet(packet: &[u8], from: Ipv4Addr) -> Option<Dmx<'_>> {
+    if packet.get(..8)? != ARTNET_ID
+        || u16::from_le_bytes(packet.get(8..10)?.try_into().ok()?) != OP_DMX
+    {
+        return None;
+    }
+    let length = u16::from_be_bytes(packet.get(16..18)?.try_into().ok()?) as usize;
+    let mut source = [0u8; 16];
+    source[..4].copy_from_slice(&from.octets());
+    Some(Dmx {
+        source,
+        priority: ARTNET_PRIORITY,
+        sequence: Some(packet[12]).filter(|s| *s != 0),
+        universe: u16::from_le_bytes([packet[14], packet[15]]),
+        terminated: false,
+        data: packet.get(18..18 + length)?,
+    })
+}
+
+/// Places universes onto the strip and arbitrates between senders.
+struct Mapper {
+    universe: u16,
+    // Offset of the first pixel in `universe`.
+    offset: usize,
+    timeout: Duration,
+    // Source owning the strip, its priority and when it was last heard.
+    active: Option<([u8; 16], u8, Instant)>,
+    // Last sequence number per source and universe.
+    sequences: Vec<([u8; 16], u16, u8)>,
+    frame: heapless::Vec<RGB8, LEDS>,
+}
+
+impl Mapper {
+    fn new(config: &RealtimeConfig, leds: usize) -> Self {
+        let mut frame = heapless::Vec::new();
+        frame.resize(leds, RGB8::default()).ok();
+        Mapper {
+            universe: config.universe,
+            offset: (config.channel.clamp(1, CHANNELS as u16) - 1) as usize,
+            timeout: Duration::from_millis(config.timeout_ms as u64),
+            active: None,
+            sequences: Vec::new(),
+            frame,
+        }
+    }
+
+    /// Pixels carried by the first universe; each following universe holds
+    /// `PIXELS_PER_UNIVERSE` starting at its first channel.
+    fn first_pixels(&self) -> usize {
+        (CHANNELS - self.offset) / 3
+    }
+
+    fn universes(&self) -> impl Iterator<Item = u16> + use<> {
+        let rest = self.frame.len().saturating_sub(self.first_pixels());
+        let count = 1 + rest.div_ceil(PIXELS_PER_UNIVERSE) as u16;
+        self.universe..self.universe.saturating_add(count)
+    }
+
+    /// Applies a packet to the frame, returning whether anything changed.
+    fn accept(&mut self, dmx: &Dmx) -> bool {
+        // Senders of other universes neither take the strip over nor fill
+        // up the sequence table.
+        if !self.universes().any(|u| u == dmx.universe) {
+            return false;
+        }
+        let now = Instant::now();
+        if let Some((source, priority, seen)) = self.active
+            && source != dmx.source
+            && now < seen + self.timeout
+            && dmx.priority <= priority
+        {
+            return false;
+        }
+        if dmx.terminated {
+            if self.active.is_some_and(|(source, ..)| source == dmx.source) {
+                self.active = None;
+            }
+            return false;
+        }
+        if !self.in_sequence(dmx) {
+            return false;
+        }
+        self.active = Some((dmx.source, dmx.priority, now));
+
+        let (first, offset) = match dmx.universe - self.universe {
+            0 => (0, self.offset),
+            i => (
+                self.first_pixels() + (i as usize - 1) * PIXELS_PER_UNIVERSE,
+                0,
+            ),
+        };
+        let Some(pixels) = self.frame.get_mut(first..) else {
+            return false;
+        };
+        let channels = dmx.data.get(offset..).unwrap_or_default();
+        for (pixel, [r, g, b]) in pixels.iter_mut().zip(channels.as_chunks::<3>().0) {
+            *pixel = RGB8::new(*r, *g, *b);
+        }
+        true
+    }
+
+    /// Drops packets that arrive late, as E1.31 prescribes: anything up to
+    /// 20 behind the last one seen is considered out of order.
+    fn in_sequence(&mut self, dmx: &Dmx) -> bool {
+        let Some(sequence) = dmx.sequence else {
+            return true;
+        };
+        let entry = self
+            .sequences
+            .iter_mut()
+            .find(|(s, u, _)| *s == dmx.source && *u == dmx.universe);
+        match entry {
+            Some((_, _, last)) => {
+                let diff = sequence.wrapping_sub(*last) as i8;
+                if diff <= 0 && diff > -20 {
+                    return false;
+                }
+                *last = sequence;
+            }
+            None => {
+                if self.sequences.len() == MAX_SEQUENCES {
+                    self.sequences.remove(0);
+                }
+                self.sequences.push((dmx.source, dmx.universe, sequence));
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,245 @@
+use alloc::{string::String, vec::Vec};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+// mDNS reuses the top bit of the class for "unicast response" in questions
+// and for "cache flush" in records.
+const CLASS_MDNS_BIT: u16 = 0x8000;
+
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub unicast: bool,
+}
+
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+pub enum RData {
+    A([u8; 4]),
+    Ptr(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Txt(Vec<String>),
+    Other(u16),
+}
+
+impl RData {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            RData::A(_) => TYPE_A,
+            RData::Ptr(_) => TYPE_PTR,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::Txt(_) => TYPE_TXT,
+            RData::Other(t) => *t,
+        }
+    }
+}
+
+pub struct Message {
+    pub id: u16,
+    pub response: bool,
+    pub questions: Vec<Question>,
+    /// Answer, authority and additional records, in that order.
+    pub records: Vec<Record>,
+}
+
+pub fn parse(buf: &[u8]) -> Option<Message> {
+    let word =
+        |i: usize| -> Option<u16> { Some(u16::from_be_bytes([*buf.get(i)?, *buf.get(i + 1)?])) };
+    let id = word(0)?;
+    let response = word(2)? & 0x8000 != 0;
+    let qdcount = word(4)?;
+    let rrcount = word(6)? as usize + word(8)? as usize + word(10)? as usize;
+
+    let mut pos = 12;
+    let mut questions = Vec::new();
+    for _ in 0..qdcount {
+        let (name, next) = read_name(buf, pos)?;
+        let qtype = word(next)?;
+        let qclass = word(next + 2)?;
+        questions.push(Question {
+            name,
+            qtype,
+            unicast: qclass & CLASS_MDNS_BIT != 0,
+        });
+        pos = next + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..rrcount {
+        let (name, next) = read_name(buf, pos)?;
+        let rtype = word(next)?;
+        let ttl = ((word(next + 4)? as u32) << 16) | word(next + 6)? as u32;
+        let len = word(next + 8)? as usize;
+        let start = next + 10;
+        let rdata = buf.get(start..start + len)?;
+        let data = match rtype {
+            TYPE_A if len == 4 => RData::A(rdata.try_into().ok()?),
+            TYPE_PTR => RData::Ptr(read_name(buf, start)?.0),
+            TYPE_SRV if len > 6 => RData::Srv {
+                priority: word(start)?,
+                weight: word(start + 2)?,
+                port: word(start + 4)?,
+                target: read_name(buf, start + 6)?.0,
+            },
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut rest = rdata;
+                while let [l, tail @ ..] = rest {
+                    let s = tail.get(..*l as usize)?;
+                    strings.push(String::from_utf8_lossy(s).into());
+                    rest = &tail[*l as usize..];
+                }
+                RData::Txt(strings)
+            }
+            t => RData::Other(t),
+        };
+        records.push(Record { name, ttl, data });
+        pos = start + len;
+    }
+
+    Some(Message {
+        id,
+        response,
+        questions,
+        records,
+    })
+}
+
+/// Reads a possibly compressed name, returning it with the position right
+/// after it in the message.
+fn read_name(buf: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Bounds the number of compression pointers followed.
+    for _ in 0..32 {
+        let len = *buf.get(pos)? as usize;
+        match len {
+            0 => return Some((name, end.unwrap_or(pos + 1))),
+            l if l & 0xc0 == 0xc0 => {
+                let target = ((l & 0x3f) << 8) | *buf.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            l => {
+                let label = buf.get(pos + 1..pos + 1 + l)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(core::str::from_utf8(label).ok()?);
+                pos += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+pub fn name_eq(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// Builds a message section by section; names are written uncompressed.
+pub struct Builder {
+    buf: Vec<u8>,
+}
+
+impl Builder {
+    pub fn new(id: u16, response: bool) -> Self {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&id.to_be_bytes());
+        // Responses are authoritative answers.
+        let flags: u16 = if response { 0x8400 } else { 0 };
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        Builder { buf }
+    }
+
+    fn bump(&mut self, count: usize) {
+        let at = 4 + 2 * count;
+        let n = u16::from_be_bytes([self.buf[at], self.buf[at + 1]]) + 1;
+        self.buf[at..at + 2].copy_from_slice(&n.to_be_bytes());
+    }
+
+    pub fn question(&mut self, name: &str, qtype: u16, unicast: bool) {
+        write_name(&mut self.buf, name);
+        self.buf.extend_from_slice(&qtype.to_be_bytes());
+        let class = CLASS_IN | if unicast { CLASS_MDNS_BIT } else { 0 };
+        self.buf.extend_from_slice(&class.to_be_bytes());
+        self.bump(0);
+    }
+
+    /// Adds an answer; `unique` sets the mDNS cache-flush bit.
+    pub fn answer(&mut self, record: &Record, unique: bool) {
+        self.record(record, unique);
+        self.bump(1);
+    }
+
+    pub fn additional(&mut self, record: &Record, unique: bool) {
+        self.record(record, unique);
+        self.bump(3);
+    }
+
+    fn record(&mut self, record: &Record, unique: bool) {
+        write_name(&mut self.buf, &record.name);
+        self.buf
+            .extend_from_slice(&record.data.rtype().to_be_bytes());
+        let class = CLASS_IN | if unique { CLASS_MDNS_BIT } else { 0 };
+        self.buf.extend_from_slice(&class.to_be_bytes());
+        self.buf.extend_from_slice(&record.ttl.to_be_bytes());
+
+        let len_at = self.buf.len();
+        self.buf.extend_from_slice(&[0, 0]);
+        match &record.data {
+            RData::A(ip) => self.buf.extend_from_slice(ip),
+            RData::Ptr(target) => write_name(&mut self.buf, target),
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                self.buf.extend_from_slice(&priority.to_be_bytes());
+                self.buf.extend_from_slice(&weight.to_be_bytes());
+                self.buf.extend_from_slice(&port.to_be_bytes());
+                write_name(&mut self.buf, target);
+            }
+            RData::Txt(strings) => {
+                for s in strings {
+                    let s = &s.as_bytes()[..s.len().min(255)];
+                    self.buf.push(s.len() as u8);
+                    self.buf.extend_from_slice(s);
+                }
+            }
+            RData::Other(_) => {}
+        }
+        let len = (self.buf.len() - len_at - 2) as u16;
+        self.buf[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
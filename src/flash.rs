@@ -0,0 +1,99 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use esp_bootloader_esp_idf::partitions::{PARTITION_TABLE_MAX_LEN, read_partition_table};
+use esp_hal::peripherals::FLASH;
+use esp_storage::{FlashStorage, FlashStorageError};
+
+// The whole SPI flash, shared by every partition view handed out below.
+static FLASH: Mutex<CriticalSectionRawMutex, RefCell<Option<FlashStorage<'static>>>> =
+    Mutex::new(RefCell::new(None));
+
+pub fn init(flash: FLASH<'static>) {
+    FLASH.lock(|f| f.replace(Some(FlashStorage::new(flash))));
+}
+
+pub fn with<R>(f: impl FnOnce(&mut FlashStorage<'static>) -> R) -> R {
+    FLASH.lock(|flash| f(flash.borrow_mut().as_mut().expect("flash not initialized")))
+}
+
+/// Looks a data partition up by its label in the partition table.
+pub fn find(label: &str) -> Option<Partition> {
+    with(|flash| {
+        let mut buf = [0u8; PARTITION_TABLE_MAX_LEN];
+        let pt = read_partition_table(flash, &mut buf).ok()?;
+        pt.iter()
+            .find(|p| p.label_as_str() == label)
+            .map(|p| Partition {
+                offset: p.offset(),
+                size: p.len(),
+            })
+    })
+}
+
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    OutOfBounds,
+    Flash(FlashStorageError),
+}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Error::Flash(e) => e.kind(),
+        }
+    }
+}
+
+/// A `NorFlash` view of one partition, addressed from its start.
+#[derive(Clone, Copy)]
+pub struct Partition {
+    offset: u32,
+    size: u32,
+}
+
+impl Partition {
+    fn address(&self, offset: u32, len: usize) -> Result<u32, Error> {
+        match offset.checked_add(len as u32) {
+            Some(end) if end <= self.size => Ok(self.offset + offset),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+}
+
+impl ErrorType for Partition {
+    type Error = Error;
+}
+
+impl ReadNorFlash for Partition {
+    const READ_SIZE: usize = FlashStorage::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let address = self.address(offset, bytes.len())?;
+        with(|f| f.read(address, bytes)).map_err(Error::Flash)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl NorFlash for Partition {
+    const WRITE_SIZE: usize = FlashStorage::WRITE_SIZE;
+    const ERASE_SIZE: usize = FlashStorage::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let from_address = self.address(from, 0)?;
+        let to_address = self.address(to, 0)?;
+        with(|f| f.erase(from_address, to_address)).map_err(Error::Flash)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let address = self.address(offset, bytes.len())?;
+        with(|f| f.write(address, bytes)).map_err(Error::Flash)
+    }
+}
@@ -0,0 +1,148 @@
+use alloc::{string::String, vec::Vec};
+use embassy_net::tcp::TcpSocket;
+use embedded_io_async::Write;
+
+const MAX_HEAD: usize = 1024;
+const MAX_BODY: usize = 2048;
+
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    Closed,
+    Malformed,
+    TooLarge,
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Looks a header up by its case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the body is CBOR rather than the default JSON.
+    pub fn is_cbor(&self) -> bool {
+        self.header("content-type")
+            .is_some_and(|t| t.starts_with("application/cbor"))
+    }
+}
+
+/// Reads one HTTP/1.1 request; only `Content-Length` bodies are supported.
+pub async fn read_request(socket: &mut TcpSocket<'_>) -> Result<Request, Error> {
+    let mut buf = Vec::new();
+    let head_end = loop {
+        let mut chunk = [0u8; 256];
+        let n = socket.read(&mut chunk).await.map_err(|_| Error::Closed)?;
+        if n == 0 {
+            return Err(Error::Closed);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(Error::TooLarge);
+        }
+    };
+
+    let head = core::str::from_utf8(&buf[..head_end]).map_err(|_| Error::Malformed)?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().ok_or(Error::Malformed)?.split(' ');
+    let method = request_line.next().ok_or(Error::Malformed)?.into();
+    let path = request_line.next().ok_or(Error::Malformed)?.into();
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().into(), v.trim().into()))
+        .collect();
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let length: usize = request
+        .header("content-length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        return Err(Error::TooLarge);
+    }
+
+    let mut body: Vec<u8> = buf[head_end + 4..].into();
+    while body.len() < length {
+        let mut chunk = [0u8; 256];
+        let n = socket.read(&mut chunk).await.map_err(|_| Error::Closed)?;
+        if n == 0 {
+            return Err(Error::Closed);
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(length);
+    request.body = body;
+
+    Ok(request)
+}
+
+pub async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), Error> {
+    let head = alloc::format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    socket
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|_| Error::Closed)?;
+    socket.write_all(body).await.map_err(|_| Error::Closed)?;
+    socket.flush().await.map_err(|_| Error::Closed)
+}
+
+/// Iterates over the decoded `name=value` pairs of a urlencoded form.
+pub fn form_fields(body: &[u8]) -> impl Iterator<Item = (String, String)> + '_ {
+    body.split(|b| *b == b'&').filter_map(|pair| {
+        let mut kv = pair.splitn(2, |b| *b == b'=');
+        let name = url_decode(kv.next()?);
+        let value = url_decode(kv.next().unwrap_or_default());
+        Some((name, value))
+    })
+}
+
+fn url_decode(input: &[u8]) -> String {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < input.len() => {
+                let hex = core::str::from_utf8(&input[i + 1..i + 3])
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into()
+}
@@ -1,14 +1,20 @@
-use core::{error::Error, iter::Map};
+use core::{cell::RefCell, error::Error, iter::Map};
 
-use alloc::{boxed::Box, vec::Vec};
-use defmt::info;
+use alloc::{boxed::Box, string::String, vec::Vec};
 use embassy_executor::task;
-use embassy_futures::{join::join, select::select};
+use embassy_futures::{
+    join::join,
+    select::{Either4, select, select4},
+};
 use embassy_sync::{
-    blocking_mutex::raw::NoopRawMutex,
+    blocking_mutex::{
+        Mutex,
+        raw::{CriticalSectionRawMutex, NoopRawMutex},
+    },
     channel::{Channel, Receiver},
+    signal::Signal,
 };
-use embassy_time::{Duration, Timer, WithTimeout};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
 use esp_hal::{
     gpio::Output,
     peripherals::{GPIO8, RMT},
@@ -19,6 +25,7 @@ use esp_hal_smartled::{
     color_order::{self, Rgb},
 };
 use minicbor::{Decode, Encode};
+use serde::{Deserialize, Serialize};
 use smart_leds::{
     RGB, SmartLedsWriteAsync, brightness, gamma,
     hsv::{Hsv, hsv2rgb},
@@ -26,9 +33,52 @@ use smart_leds::{
 use smart_leds_trait::RGB8;
 use static_cell::StaticCell;
 
+use crate::config::{Config, EffectConfig, PowerOn, Preset, SharedConfig};
+use crate::power::{self, Relay};
+use crate::state;
+use crate::timebase;
+use crate::watchdog::{self, Watched};
+use crate::{error, info, warn};
+
 static CH: StaticCell<Channel<NoopRawMutex, Ready, 3>> = StaticCell::new();
 
-const LEDS: usize = 50;
+pub use wink_core::config::LEDS;
+
+const ANIMATION_FRAME: Duration = Duration::from_millis(40);
+// Bounds the config record, which has to fit a flash sector.
+const MAX_PRESETS: usize = 16;
+
+/// Last state rendered by `receive_light`.
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    on: false,
+    brightness: 0,
+    effect: EffectConfig {
+        hue: 0,
+        sat: 0,
+        speed: 0,
+    },
+}));
+
+pub fn state() -> State {
+    STATE.lock(|s| s.borrow().clone())
+}
+
+/// Colors of the last frame, before gamma correction and brightness.
+static FRAME: Mutex<CriticalSectionRawMutex, RefCell<heapless::Vec<RGB8, LEDS>>> =
+    Mutex::new(RefCell::new(heapless::Vec::new()));
+
+pub fn frame() -> heapless::Vec<RGB8, LEDS> {
+    FRAME.lock(|f| f.borrow().clone())
+}
+
+/// Pixels from a realtime protocol, shown as they are instead of the effect
+/// until `timeout` passes without another frame.
+pub struct Realtime {
+    pub pixels: heapless::Vec<RGB8, LEDS>,
+    pub timeout: Duration,
+}
+
+pub static REALTIME: Signal<CriticalSectionRawMutex, Realtime> = Signal::new();
 
 pub struct StripMisc<'a> {
     rmt: RMT<'a>,
@@ -76,14 +126,52 @@ pub fn init<'a>(
     (s_m.ch, led)
 }
 
-fn get_red(val: u8) -> Hsv {
+/// `time` is the shared time base in milliseconds.
+fn get_color(effect: &EffectConfig, val: u8, time: u64) -> Hsv {
     Hsv {
-        hue: 0,
-        sat: 255,
+        hue: effect
+            .hue
+            .wrapping_add((time * effect.speed as u64 / 1000) as u8),
+        sat: effect.sat,
         val,
     }
 }
 
+/// A transition from what was shown towards the current target.
+struct Fade {
+    level: u8,
+    effect: EffectConfig,
+    start: Instant,
+    over: Duration,
+}
+
+impl Fade {
+    /// Level and effect to show at `now`, or `None` once `level` and
+    /// `effect` are reached.
+    fn at(&self, now: Instant, level: u8, effect: &EffectConfig) -> Option<(u8, EffectConfig)> {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.over {
+            return None;
+        }
+        let p = (elapsed.as_millis() * 256 / self.over.as_millis()) as i32;
+        let mix = |from: u8, to: u8| (from as i32 + (to as i32 - from as i32) * p / 256) as u8;
+        // Hues go round the shorter way.
+        let turn = effect.hue.wrapping_sub(self.effect.hue) as i8 as i32;
+        Some((
+            mix(self.level, level),
+            EffectConfig {
+                hue: self.effect.hue.wrapping_add((turn * p / 256) as u8),
+                sat: mix(self.effect.sat, effect.sat),
+                speed: effect.speed,
+            },
+        ))
+    }
+}
+
+fn find_preset(config: &Config, id: u8) -> Option<Preset> {
+    config.presets.iter().find(|p| p.id == id).cloned()
+}
+
 #[task]
 pub async fn receive_light(
     ch: &'static Channel<NoopRawMutex, Ready, 3>,
@@ -95,20 +183,214 @@ pub async fn receive_light(
         color_order::Grb,
         Ws2812bTiming,
     >,
+    commands: &'static Receiver<'static, NoopRawMutex, Command, 3>,
+    config: &'static SharedConfig,
+    saved: Option<State>,
+    mut relay: Relay,
 ) {
+    let (strip, mut effect, power_on, limit) = {
+        let config = config.lock().await;
+        let power_on = match config.get().power_on {
+            PowerOn::Off => None,
+            PowerOn::Last => saved,
+            PowerOn::Preset(id) => find_preset(config.get(), id).map(|p| State {
+                on: p.on,
+                brightness: p.brightness,
+                effect: p.effect,
+            }),
+        };
+        (
+            config.get().strip.clone(),
+            config.get().effect.clone(),
+            power_on,
+            config.get().power.limit.clone(),
+        )
+    };
+    let leds = (strip.leds as usize).min(LEDS);
+    let mut on = false;
+    let mut bright = strip.brightness;
+    if let Some(state) = power_on {
+        (on, bright, effect) = (state.on, state.brightness, state.effect);
+    }
+    // Set while the strip does not show the state, as at boot or after a
+    // status display.
+    let mut redraw = true;
+    // Set while realtime data overrides the effect.
+    let mut realtime_until: Option<Instant> = None;
+    let mut fade: Option<Fade> = None;
+    // Level and effect of the last frame, where a fade starts from.
+    let mut shown = (0u8, effect.clone());
+
     loop {
-        let n_ready = ch.receive().await;
+        watchdog::check_in(Watched::Render);
+        relay.poll();
+        // Either the end of realtime data or the next frame of an animation.
+        let wake = match realtime_until {
+            Some(at) => Some(at),
+            None if redraw || fade.is_some() || on && effect.speed != 0 => {
+                Some(Instant::now() + ANIMATION_FRAME)
+            }
+            None => None,
+        };
+        let timeout = async {
+            match wake {
+                Some(at) => Timer::at(at).await,
+                // Idle, but still expected to check in.
+                None => Timer::after(watchdog::CHECK_IN).await,
+            }
+        };
+        let mut animation = false;
+        // Only set for status displays, which replace the effect for a while.
+        let status = match select4(ch.receive(), commands.receive(), REALTIME.wait(), timeout).await {
+            Either4::First(_) if realtime_until.is_some() => continue,
+            Either4::First(n_ready) => {
+                let enlight = n_ready.enlight + n_ready.blink;
+                Some([0; LEDS].map(|val| if val < enlight { 255u8 } else { 0u8 }))
+            }
+            Either4::Second(Command::Light(light)) => {
+                info!("{:?}", light);
+                on = light.on;
+                fade = None;
+                None
+            }
+            Either4::Second(Command::Effect(e)) => {
+                effect = e;
+                fade = None;
+                None
+            }
+            Either4::Second(Command::Fade {
+                on: to_on,
+                effect: to_effect,
+                over,
+            }) => {
+                fade = Some(Fade {
+                    level: shown.0,
+                    effect: shown.1.clone(),
+                    start: Instant::now(),
+                    over,
+                });
+                on = to_on;
+                if let Some(e) = to_effect {
+                    effect = e;
+                }
+                animation = true;
+                None
+            }
+            Either4::Second(Command::Preset { id, over }) => {
+                let Some(preset) = find_preset(config.lock().await.get(), id) else {
+                    warn!("No preset {}", id);
+                    continue;
+                };
+                fade = Some(Fade {
+                    level: shown.0,
+                    effect: shown.1.clone(),
+                    start: Instant::now(),
+                    over,
+                });
+                (on, bright, effect) = (preset.on, preset.brightness, preset.effect);
+                animation = true;
+                None
+            }
+            Either4::Second(Command::SavePreset { id, name }) => {
+                let preset = Preset {
+                    id,
+                    name,
+                    on,
+                    brightness: bright,
+                    effect: effect.clone(),
+                };
+                let mut config = config.lock().await;
+                let presets = &config.get().presets;
+                if presets.len() >= MAX_PRESETS && presets.iter().all(|p| p.id != id) {
+                    warn!("No room for preset {}", id);
+                    continue;
+                }
+                let res = config.update(|c| {
+                    c.presets.retain(|p| p.id != id);
+                    c.presets.push(preset);
+                });
+                match res {
+                    Ok(_) => info!("Saved preset {}", id),
+                    Err(e) => error!("failed to save preset: {:?}", e),
+                }
+                continue;
+            }
+            Either4::Third(realtime) => {
+                if realtime_until.is_none() {
+                    info!("Realtime data, effect suspended");
+                }
+                realtime_until =
+                    Some(Instant::now().checked_add(realtime.timeout).unwrap_or(Instant::MAX));
+                FRAME.lock(|f| f.replace(realtime.pixels.clone()));
+                let black = bright == 0 || realtime.pixels.iter().all(|p| *p == RGB8::default());
+                if relay.frame(black).await {
+                    let pixels = &realtime.pixels[..leds.min(realtime.pixels.len())];
+                    let level = power::limit_frame(pixels, bright, limit.as_ref());
+                    // Senders already send corrected levels.
+                    let b = brightness(pixels.iter().copied(), level);
+                    smart_leds.write(b).await.unwrap();
+                }
+                continue;
+            }
+            Either4::Fourth(_) => {
+                if realtime_until.take().is_some() {
+                    info!("Realtime data timed out");
+                } else if wake.is_none() {
+                    continue;
+                } else {
+                    animation = true;
+                }
+                None
+            }
+        };
+        redraw = status.is_some();
+        let state = State {
+            on,
+            brightness: bright,
+            effect: effect.clone(),
+        };
+        if STATE.lock(|s| s.replace(state.clone())) != state {
+            state::CHANGED.signal(state);
+        }
+        if realtime_until.is_some() {
+            continue;
+        }
+
+        let level = if on { 255u8 } else { 0u8 };
+        let current = fade.as_ref().and_then(|f| f.at(Instant::now(), level, &effect));
+        if current.is_none() {
+            fade = None;
+        }
+        let current = current.unwrap_or((level, effect.clone()));
+        let vals = match status {
+            Some(vals) => vals,
+            None => {
+                shown = current.clone();
+                [current.0; LEDS]
+            }
+        };
 
-        let enlight = n_ready.enlight + n_ready.blink;
-        let colors = [0; LEDS]
+        let time = timebase::now() / 1000;
+        let colors: heapless::Vec<RGB8, LEDS> = vals
             .iter()
-            .map(|val| if val < &enlight { 255u8 } else { 0u8 })
-            .map(|val| { get_red(val) })
-            .map(hsv2rgb);
+            .take(leds)
+            .map(|val| { get_color(&current.1, *val, time) })
+            .map(hsv2rgb)
+            .collect();
+        FRAME.lock(|f| f.replace(colors.clone()));
+        let black = bright == 0 || colors.iter().all(|c| *c == RGB8::default());
+        if !relay.frame(black).await {
+            continue;
+        }
 
-        let g = gamma(colors);
-        let b = brightness(g, 10);
+        let g: heapless::Vec<RGB8, LEDS> = gamma(colors.into_iter()).collect();
+        let level = power::limit_frame(&g, bright, limit.as_ref());
+        let b = brightness(g.into_iter(), level);
         let fut = smart_leds.write(b);
+        if animation {
+            fut.await.unwrap();
+            continue;
+        }
 
         let (_, res) = join(Timer::after_millis(500), fut).await;
         res.unwrap();
@@ -221,7 +503,57 @@ pub async fn led_task(
 //     events: Led
 // }
 
-#[derive(defmt::Format, Encode, Decode)]
+/// Everything that can drive the strip, from MQTT as well as the HTTP API.
+pub enum Command {
+    Light(Light),
+    Effect(EffectConfig),
+    /// Moves to a new state gradually, keeping the effect when `None`.
+    Fade {
+        on: bool,
+        effect: Option<EffectConfig>,
+        over: Duration,
+    },
+    /// Recalls a stored preset, fading over `over`.
+    Preset { id: u8, over: Duration },
+    /// Stores what is shown as preset `id`, replacing any with that id.
+    SavePreset { id: u8, name: String },
+}
+
+/// Recalls preset `id`, or stores the current state as it when `save` names
+/// it. The keys follow those of `Light`, so both can share an MQTT topic.
+#[derive(Encode, Decode, Deserialize)]
+#[cbor(map)]
+pub struct PresetCall {
+    #[n(2)]
+    id: u8,
+    #[n(3)]
+    save: Option<String>,
+}
+
+impl From<PresetCall> for Command {
+    fn from(call: PresetCall) -> Self {
+        match call.save {
+            Some(name) => Command::SavePreset { id: call.id, name },
+            None => Command::Preset {
+                id: call.id,
+                over: Duration::from_secs(0),
+            },
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Encode, Decode)]
+#[cbor(map)]
+pub struct State {
+    #[n(0)]
+    pub on: bool,
+    #[n(1)]
+    pub brightness: u8,
+    #[n(2)]
+    pub effect: EffectConfig,
+}
+
+#[derive(defmt::Format, Encode, Decode, Deserialize)]
 #[cbor(map)]
 pub struct Light {
     #[n(0)]
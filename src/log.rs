@@ -0,0 +1,420 @@
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use core::ptr::{addr_of, addr_of_mut};
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use critical_section::RestoreState;
+use embassy_executor::task;
+use embassy_net::{
+    IpEndpoint, Stack,
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::Printer;
+use minicbor::{Decode, Encode};
+
+use crate::clock::{self, DateTime};
+use crate::config::{LogConfig, LogSink, ModuleLevel, SharedConfig};
+use crate::wifi;
+
+pub use wink_core::log::Level;
+
+// Records kept while the sink is unreachable or over its rate; the oldest
+// go first.
+const MAX_QUEUED: usize = 64;
+// Longest defmt frame mirrored; records with a longer one go without it.
+const MAX_FRAME: usize = 256;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// local0, where syslog servers expect applications to log.
+const FACILITY: u8 = 16;
+// The enterprise number RFC 5612 sets aside for examples, as nothing else
+// defines a place for defmt frames.
+const SD_ID: &str = "defmt@32473";
+const APP_NAME: &str = "wink";
+
+/// A log record as mirrored to the sink.
+#[derive(Encode)]
+#[cbor(map)]
+pub struct Record {
+    #[n(0)]
+    pub level: Level,
+    #[n(1)]
+    pub module: &'static str,
+    #[n(2)]
+    pub uptime_ms: u64,
+    // Unix time in milliseconds, once SNTP has set the clock.
+    #[n(3)]
+    pub time: Option<u64>,
+    // The format string; the arguments are only in `frame`.
+    #[n(4)]
+    pub text: &'static str,
+    // The record as defmt encoded it, for decoding with the firmware's ELF
+    // file like the probe output.
+    #[n(5)]
+    #[cbor(with = "minicbor::bytes")]
+    pub frame: Vec<u8>,
+}
+
+/// Changes the level of one module, or the level of all the others when no
+/// module is named. Without a level, the module follows the others again.
+#[derive(Decode)]
+#[cbor(map)]
+pub struct LevelCommand {
+    #[n(0)]
+    pub module: Option<String>,
+    #[n(1)]
+    pub level: Option<Level>,
+}
+
+impl LevelCommand {
+    pub fn apply(self, config: &mut LogConfig) {
+        match (self.module, self.level) {
+            (None, level) => config.local = level.unwrap_or_default(),
+            (Some(module), level) => {
+                config.modules.retain(|m| m.module != module);
+                if let Some(level) = level {
+                    config.modules.push(ModuleLevel { module, level });
+                }
+            }
+        }
+    }
+}
+
+// Lowest level logged at all, and the exceptions by module.
+static LOCAL: Mutex<CriticalSectionRawMutex, RefCell<(Level, Vec<ModuleLevel>)>> =
+    Mutex::new(RefCell::new((Level::Info, Vec::new())));
+// Lowest level mirrored, while there is a sink.
+static REMOTE: Mutex<CriticalSectionRawMutex, Cell<Option<Level>>> = Mutex::new(Cell::new(None));
+static QUEUE: Mutex<CriticalSectionRawMutex, RefCell<VecDeque<Record>>> =
+    Mutex::new(RefCell::new(VecDeque::new()));
+// Set while the defmt frame of a mirrored record is being written. Fixed in
+// size, as the logger must not allocate.
+static CAPTURE: Mutex<CriticalSectionRawMutex, RefCell<Option<heapless::Vec<u8, MAX_FRAME>>>> =
+    Mutex::new(RefCell::new(None));
+static QUEUED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Logs through defmt like `defmt::debug!`, unless the module's level is
+/// higher, and mirrors the record to the configured sink.
+#[macro_export]
+macro_rules! debug {
+    ($fmt:literal $($arg:tt)*) => {
+        $crate::log::record($crate::log::Level::Debug, module_path!(), $fmt, || {
+            ::defmt::debug!($fmt $($arg)*)
+        })
+    };
+}
+
+/// Like `debug!`, for `defmt::info!`.
+#[macro_export]
+macro_rules! info {
+    ($fmt:literal $($arg:tt)*) => {
+        $crate::log::record($crate::log::Level::Info, module_path!(), $fmt, || {
+            ::defmt::info!($fmt $($arg)*)
+        })
+    };
+}
+
+/// Like `debug!`, for `defmt::warn!`.
+#[macro_export]
+macro_rules! warn {
+    ($fmt:literal $($arg:tt)*) => {
+        $crate::log::record($crate::log::Level::Warn, module_path!(), $fmt, || {
+            ::defmt::warn!($fmt $($arg)*)
+        })
+    };
+}
+
+/// Like `debug!`, for `defmt::error!`.
+#[macro_export]
+macro_rules! error {
+    ($fmt:literal $($arg:tt)*) => {
+        $crate::log::record($crate::log::Level::Error, module_path!(), $fmt, || {
+            ::defmt::error!($fmt $($arg)*)
+        })
+    };
+}
+
+/// Applies the levels in `config`, and starts mirroring records at
+/// `config.level` and above if there is a sink. Logs from other crates are
+/// neither filtered nor mirrored.
+pub fn init(config: &LogConfig) {
+    LOCAL.lock(|l| l.replace((config.local, config.modules.clone())));
+    let level = match config.sink {
+        LogSink::None => None,
+        _ => Some(config.level),
+    };
+    REMOTE.lock(|r| r.set(level));
+}
+
+#[doc(hidden)]
+pub fn record(level: Level, module: &'static str, text: &'static str, log: impl FnOnce()) {
+    let module = module.split_once("::").map_or(module, |(_, m)| m);
+    let min = LOCAL.lock(|l| {
+        let (local, modules) = &*l.borrow();
+        modules
+            .iter()
+            .find(|m| m.module == module)
+            .map_or(*local, |m| m.level)
+    });
+    if level < min {
+        return;
+    }
+    if REMOTE.lock(Cell::get).is_none_or(|min| level < min) {
+        log();
+        return;
+    }
+    let frame = critical_section::with(|_| {
+        CAPTURE.lock(|c| c.replace(Some(heapless::Vec::new())));
+        log();
+        CAPTURE.lock(|c| c.take())
+    });
+    let record = Record {
+        level,
+        module,
+        uptime_ms: Instant::now().as_millis(),
+        time: clock::utc_micros().map(|t| t / 1000),
+        text,
+        frame: frame.map_or_else(Vec::new, |f| f.to_vec()),
+    };
+    QUEUE.lock(|q| {
+        let mut queue = q.borrow_mut();
+        if queue.len() >= MAX_QUEUED {
+            queue.pop_front();
+        }
+        queue.push_back(record);
+    });
+    QUEUED.signal(());
+}
+
+/// Sends no more than `per_window` records per window.
+pub struct Limiter {
+    per_window: u16,
+    start: Instant,
+    sent: u16,
+}
+
+impl Limiter {
+    pub fn new(per_minute: u16) -> Self {
+        Limiter {
+            per_window: per_minute,
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+}
+
+/// The next record to send, once the limiter allows it. Cancel safe, as the
+/// record is only taken off the queue when this returns.
+pub async fn next(limiter: &mut Limiter) -> Record {
+    loop {
+        if limiter.start.elapsed() >= RATE_WINDOW {
+            limiter.start = Instant::now();
+            limiter.sent = 0;
+        }
+        if limiter.sent >= limiter.per_window {
+            Timer::at(limiter.start + RATE_WINDOW).await;
+            continue;
+        }
+        if let Some(record) = QUEUE.lock(|q| q.borrow_mut().pop_front()) {
+            limiter.sent += 1;
+            return record;
+        }
+        QUEUED.wait().await;
+    }
+}
+
+/// Puts back a record the sink failed to take, to be sent first.
+pub fn requeue(record: Record) {
+    QUEUE.lock(|q| {
+        let mut queue = q.borrow_mut();
+        if queue.len() < MAX_QUEUED {
+            queue.push_front(record);
+        }
+    });
+}
+
+/// Sends records to the configured syslog server as RFC 5424 messages over
+/// UDP. The MQTT sink is served by the MQTT task.
+#[task]
+pub async fn syslog_task(stack: Stack<'static>, config: &'static SharedConfig) {
+    let (sink, rate, hostname) = {
+        let config = config.lock().await;
+        let log = &config.get().log;
+        (
+            log.sink.clone(),
+            log.rate,
+            wifi::device_name(&config.get().net),
+        )
+    };
+    let LogSink::Syslog { host, port } = sink else {
+        return;
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(0) {
+        defmt::error!("syslog bind error: {:?}", e);
+        return;
+    }
+
+    let mut limiter = Limiter::new(rate);
+    let mut server = None;
+    loop {
+        let record = next(&mut limiter).await;
+        stack.wait_config_up().await;
+        if server.is_none() {
+            server = resolve(stack, &host, port).await;
+        }
+        let Some(endpoint) = server else {
+            requeue(record);
+            Timer::after(Duration::from_secs(10)).await;
+            continue;
+        };
+        let message = syslog_message(&record, &hostname);
+        // Errors are only reported to the probe, so that a failing sink does
+        // not feed itself.
+        if let Err(e) = socket.send_to(message.as_bytes(), endpoint).await {
+            defmt::warn!("syslog send error: {:?}", e);
+            requeue(record);
+            server = None;
+        }
+    }
+}
+
+async fn resolve(stack: Stack<'static>, host: &str, port: u16) -> Option<IpEndpoint> {
+    if let Ok(ip) = host.parse::<core::net::Ipv4Addr>() {
+        return Some(IpEndpoint::new(ip.into(), port));
+    }
+    match stack.dns_query(host, DnsQueryType::A).await {
+        Ok(addrs) => addrs.first().map(|ip| IpEndpoint::new(*ip, port)),
+        Err(e) => {
+            defmt::warn!("cannot resolve syslog server {}: {:?}", host, e);
+            None
+        }
+    }
+}
+
+/// Formats a record as an RFC 5424 message, with the defmt frame as
+/// structured data.
+fn syslog_message(record: &Record, hostname: &str) -> String {
+    let mut message = String::new();
+    let pri = FACILITY * 8 + record.level.severity();
+    write!(message, "<{}>1 ", pri).ok();
+    match record.time {
+        Some(ms) => {
+            let t = DateTime::from_unix((ms / 1000) as i64);
+            write!(
+                message,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                t.year,
+                t.month,
+                t.day,
+                t.hour,
+                t.minute,
+                t.second,
+                ms % 1000
+            )
+            .ok();
+        }
+        None => message.push('-'),
+    }
+    write!(
+        message,
+        " {} {} - {} [{} frame=\"",
+        hostname, APP_NAME, record.module, SD_ID
+    )
+    .ok();
+    for b in &record.frame {
+        write!(message, "{:02x}", b).ok();
+    }
+    write!(message, "\"] {}", record.text).ok();
+    message
+}
+
+/// The defmt logger, framing records for espflash like `esp-println` does
+/// and copying those being mirrored.
+#[defmt::global_logger]
+struct Logger;
+
+static mut TAKEN: bool = false;
+// Frames started while another was being written, which are dropped.
+static mut NESTED: u8 = 0;
+static mut RESTORE: RestoreState = RestoreState::invalid();
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+/// Whether the defmt logger is in the middle of a record, as when a panic
+/// is raised while logging.
+pub fn is_taken() -> bool {
+    // SAFETY: a plain read of a flag only set with interrupts masked.
+    unsafe { addr_of!(TAKEN).read_volatile() }
+}
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        // SAFETY: released in `release`, which defmt always calls next.
+        let restore = unsafe { critical_section::acquire() };
+        // SAFETY: the statics are only touched inside the critical section.
+        unsafe {
+            if TAKEN {
+                // Logging from within a frame, as a panic raised there does.
+                // The nested frame would corrupt the one being written.
+                NESTED += 1;
+                critical_section::release(restore);
+                return;
+            }
+            TAKEN = true;
+            RESTORE = restore;
+        }
+        // Tells espflash where a defmt frame starts.
+        Printer::write_bytes(&[0xFF, 0x00]);
+        unsafe { (*addr_of_mut!(ENCODER)).start_frame(write) }
+    }
+
+    unsafe fn release() {
+        unsafe {
+            if NESTED > 0 {
+                NESTED -= 1;
+                return;
+            }
+            (*addr_of_mut!(ENCODER)).end_frame(write);
+            TAKEN = false;
+            critical_section::release(RESTORE);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn write(bytes: &[u8]) {
+        unsafe {
+            if NESTED == 0 {
+                (*addr_of_mut!(ENCODER)).write(bytes, write)
+            }
+        }
+    }
+}
+
+fn write(bytes: &[u8]) {
+    Printer::write_bytes(bytes);
+    CAPTURE.lock(|c| {
+        let mut capture = c.borrow_mut();
+        if let Some(frame) = capture.as_mut()
+            && frame.extend_from_slice(bytes).is_err()
+        {
+            *capture = None;
+        }
+    });
+}
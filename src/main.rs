@@ -5,14 +5,37 @@
     reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
     holding buffers for the duration of a data transfer."
 )]
+mod api;
+mod clock;
+mod config;
+mod crash;
+mod dmx;
+mod dns;
+mod flash;
+mod http;
 mod led;
+mod log;
+mod mdns;
 mod mqtt;
+mod ota;
+mod portal;
+mod power;
+mod realtime;
+mod schedule;
+mod sntp;
+mod state;
+mod telemetry;
+mod timebase;
+mod version;
+mod watchdog;
+mod wifi;
+mod ws;
+
+use core::net::Ipv4Addr;
 
 use alloc::boxed::Box;
-use defmt::error;
-use defmt::info;
 use embassy_executor::Spawner;
-use embassy_net::DhcpConfig;
+use embassy_net::{DhcpConfig, Ipv4Cidr, StaticConfigV4};
 use embassy_net::{Runner, StackResources};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
@@ -20,24 +43,31 @@ use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
 use embassy_time::{Duration, Timer};
 use esp_hal::peripherals;
+use esp_hal::rtc_cntl::Rtc;
 use esp_hal::{clock::CpuClock, rng::Rng, timer::timg::TimerGroup};
-use esp_radio::wifi::{
-    ClientConfig, ModeConfig, ScanConfig, WifiController, WifiDevice, WifiEvent, WifiStaState,
-};
+use esp_radio::wifi::WifiDevice;
 use static_cell::StaticCell;
 
-use crate::led::Light;
+use crate::config::{ConfigStore, NetConfig, SharedConfig, Store};
+use crate::led::Command;
 use crate::led::Ready;
 
 use {esp_backtrace as _, esp_println as _};
 extern crate alloc;
 
-const SSID: &str = env!("SSID");
-const PASSWORD: &str = env!("PASSWORD");
-
-// This creates a default app-descriptor required by the esp-idf bootloader.
+// This creates the app-descriptor required by the esp-idf bootloader, carrying
+// the version and build time that `build.rs` determined.
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
-esp_bootloader_esp_idf::esp_app_desc!();
+esp_bootloader_esp_idf::esp_app_desc!(
+    version::VERSION,
+    env!("CARGO_PKG_NAME"),
+    version::BUILD_TIME,
+    version::BUILD_DATE,
+    esp_bootloader_esp_idf::ESP_IDF_COMPATIBLE_VERSION,
+    esp_bootloader_esp_idf::MMU_PAGE_SIZE,
+    0,
+    u16::MAX
+);
 
 
 #[esp_rtos::main]
@@ -50,6 +80,9 @@ async fn main(spawner: Spawner) -> ! {
 
     esp_alloc::heap_allocator!(size: 128 * 1024);
 
+    watchdog::init();
+    crash::init();
+
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     let sw_interrupt =
         esp_hal::interrupt::software::SoftwareInterruptControl::new(peripherals.SW_INTERRUPT);
@@ -57,32 +90,57 @@ async fn main(spawner: Spawner) -> ! {
 
     info!("Embassy initialized!");
 
+    flash::init(peripherals.FLASH);
+    let on_trial = ota::start_trial();
+    let cfg_partition = flash::find("wink_cfg").expect("no wink_cfg partition");
+    let shared_config: &'static SharedConfig =
+        Box::leak(Box::new(Mutex::new(ConfigStore::open(cfg_partition))));
+    log::init(&shared_config.lock().await.get().log);
+    let mut state_store = Store::new(flash::find("wink_state").expect("no wink_state partition"));
+    let saved = state::load(&mut state_store);
+
     let cr = Box::leak(Box::new(esp_radio::init().unwrap()));
     let (mut controller, interfaces) =
         esp_radio::wifi::new(cr, peripherals.WIFI, Default::default()).unwrap();
 
+    let power = shared_config.lock().await.get().power.clone();
     controller
-        .set_power_saving(esp_radio::wifi::PowerSaveMode::Maximum)
+        .set_power_saving(power::wifi_mode(power.wifi))
         .unwrap();
 
     let wifi_interface = interfaces.sta;
 
-    let config = embassy_net::Config::dhcpv4(DhcpConfig::default());
+    let config = stack_config(&shared_config.lock().await.get().net);
 
     let rng = Rng::new();
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
-    // Init network stack
+    // Init network stack. Sockets that can be open at once: TCP for MQTT, two
+    // API servers and the OTA fetch; UDP for E1.31, Art-Net, DDP, WLED sync,
+    // the time base, SNTP and syslog; the mDNS responder plus a browse while
+    // MQTT looks for the broker; and the stack's own DHCP and DNS sockets.
     let (stack, runner) = embassy_net::new(
         wifi_interface,
         config,
-        Box::leak(Box::new(StackResources::<3>::new())),
+        Box::leak(Box::new(StackResources::<15>::new())),
+        seed,
+    );
+
+    let ap_config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(portal::AP_ADDRESS, 24),
+        gateway: Some(portal::AP_ADDRESS),
+        dns_servers: Default::default(),
+    });
+    let (ap_stack, ap_runner) = embassy_net::new(
+        interfaces.ap,
+        ap_config,
+        Box::leak(Box::new(StackResources::<4>::new())),
         seed,
     );
 
     // let l_channel = Channel::<NoopRawMutex, u32, 3>::new();
 
-    let led_channel = Box::leak(Box::new(Channel::<NoopRawMutex, Light, 3>::new()));
+    let led_channel = Box::leak(Box::new(Channel::<NoopRawMutex, Command, 3>::new()));
 
     let led_receiver = Box::leak(Box::new(led_channel.receiver()));
     let led_sender = Box::leak(Box::new(led_channel.sender()));
@@ -95,20 +153,41 @@ async fn main(spawner: Spawner) -> ! {
     let led_status: &mut Channel<NoopRawMutex, Ready, 3> =
         Box::leak(Box::new(Channel::<NoopRawMutex, Ready, 3>::new()));
 
-    spawner.spawn(connection(controller)).ok();
+    spawner.spawn(wifi::connection(controller, shared_config)).ok();
     spawner.spawn(net_task(runner)).ok();
+    spawner.spawn(net_task(ap_runner)).ok();
+    spawner.spawn(portal::portal_task(ap_stack, shared_config)).ok();
+    spawner.spawn(mdns::mdns_task(stack, shared_config)).ok();
+    spawner.spawn(dmx::dmx_task(stack, shared_config)).ok();
+    spawner.spawn(realtime::realtime_task(stack, shared_config)).ok();
+    spawner.spawn(timebase::timebase_task(stack, shared_config)).ok();
+    spawner.spawn(sntp::sntp_task(stack, shared_config)).ok();
+    spawner.spawn(log::syslog_task(stack, shared_config)).ok();
 
 
 
     let m = led::StripMisc::new(peripherals.RMT, rmt_pin).unwrap();
     
     let (ch, s_l) = led::init(m);
-    spawner.spawn(led::receive_light(ch, s_l)).ok();
+    let ch: &'static Channel<NoopRawMutex, Ready, 3> = ch;
+    let relay = power::Relay::new(power.relay.as_ref());
+    spawner.spawn(led::receive_light(ch, s_l, led_receiver, shared_config, saved, relay)).ok();
+    spawner.spawn(state::state_task(state_store)).ok();
+    let rtc = Rtc::new(peripherals.LPWR);
+    spawner.spawn(watchdog::supervisor_task(timg0.wdt, rtc.rwdt)).ok();
     
     
 
     
-    // spawner.spawn(mqtt::mqtt_task(stack, led_sender, led_status)).ok();
+    spawner.spawn(mqtt::mqtt_task(stack, led_sender, ch, shared_config)).ok();
+    for _ in 0..2 {
+        spawner.spawn(api::api_task(stack, led_sender, shared_config)).ok();
+    }
+    spawner.spawn(schedule::schedule_task(led_sender, shared_config)).ok();
+    spawner.spawn(ota::ota_task(stack, shared_config)).ok();
+    if on_trial {
+        spawner.spawn(ota::trial_task()).ok();
+    }
     // spawner.spawn(led::led_task(led_receiver, peripherals.RMT, rmt_pin, led_status)).ok();
 
     loop {
@@ -134,50 +213,30 @@ async fn main(spawner: Spawner) -> ! {
     }
 }
 
-#[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>) {
-    info!("start connection task");
-    info!("Device capabilities: {:?}", controller.capabilities());
-    loop {
-        if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            Timer::after(Duration::from_millis(5000)).await
+fn stack_config(net: &NetConfig) -> embassy_net::Config {
+    match &net.ipv4 {
+        Some(ip) if ip.prefix <= 32 => {
+            info!("Using static address {}/{}", Ipv4Addr::from(ip.address), ip.prefix);
+            embassy_net::Config::ipv4_static(StaticConfigV4 {
+                address: Ipv4Cidr::new(ip.address.into(), ip.prefix),
+                gateway: ip.gateway.map(Into::into),
+                dns_servers: ip.dns.iter().take(3).map(|a| (*a).into()).collect(),
+            })
         }
-        if !matches!(controller.is_started(), Ok(true)) {
-            let station_config = ModeConfig::Client(
-                ClientConfig::default()
-                    .with_ssid(SSID.into())
-                    .with_password(PASSWORD.into()),
-            );
-            controller.set_config(&station_config).unwrap();
-            info!("Starting wifi");
-            controller.start_async().await.unwrap();
-            info!("Wifi started!");
-
-            info!("Scan");
-            let scan_config = ScanConfig::default().with_max(5);
-            let result = controller
-                .scan_with_config_async(scan_config)
-                .await
-                .unwrap();
-            for ap in result {
-                info!("{:?}", ap);
-            }
-        }
-        info!("About to connect...");
-
-        match controller.connect_async().await {
-            Ok(_) => info!("Wifi connected!"),
-            Err(e) => {
-                error!("Failed to connect to wifi: {:?}", e);
-                Timer::after(Duration::from_millis(5000)).await
+        ipv4 => {
+            if let Some(ip) = ipv4 {
+                warn!("Prefix /{} out of range, using DHCP", ip.prefix);
             }
+            let mut dhcp = DhcpConfig::default();
+            dhcp.hostname = Some(net.hostname.as_str())
+                .filter(|h| !h.is_empty())
+                .and_then(|h| h.try_into().ok());
+            embassy_net::Config::dhcpv4(dhcp)
         }
     }
 }
 
-#[embassy_executor::task]
+#[embassy_executor::task(pool_size = 2)]
 async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }
@@ -0,0 +1,306 @@
+use core::net::Ipv4Addr;
+
+use alloc::{format, string::String, vec, vec::Vec};
+use embassy_executor::task;
+use embassy_net::{
+    IpEndpoint, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Instant, Timer, with_deadline};
+
+use crate::config::SharedConfig;
+use crate::dns::{self, Builder, RData, Record};
+use crate::wifi;
+use crate::{error, info, warn};
+
+pub const MDNS_ADDRESS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+pub const HTTP_PORT: u16 = 80;
+
+const SERVICES: [&str; 2] = ["_wink._tcp.local", "_http._tcp.local"];
+const SERVICE_ENUMERATION: &str = "_services._dns-sd._udp.local";
+const TTL: u32 = 120;
+// How long `browse` collects answers for.
+const BROWSE_TIME: Duration = Duration::from_secs(2);
+
+/// What the responder knows about this node.
+struct Host {
+    name: String,
+    hostname: String,
+    ip: [u8; 4],
+    txt: Vec<String>,
+}
+
+impl Host {
+    fn instance(&self, service: &str) -> String {
+        format!("{}.{}", self.name, service)
+    }
+
+    fn a(&self) -> Record {
+        Record {
+            name: self.hostname.clone(),
+            ttl: TTL,
+            data: RData::A(self.ip),
+        }
+    }
+
+    fn srv(&self, service: &str) -> Record {
+        Record {
+            name: self.instance(service),
+            ttl: TTL,
+            data: RData::Srv {
+                priority: 0,
+                weight: 0,
+                port: HTTP_PORT,
+                target: self.hostname.clone(),
+            },
+        }
+    }
+
+    fn txt(&self, service: &str) -> Record {
+        Record {
+            name: self.instance(service),
+            ttl: TTL,
+            data: RData::Txt(self.txt.clone()),
+        }
+    }
+
+    fn ptr(&self, service: &str) -> Record {
+        Record {
+            name: service.into(),
+            ttl: TTL,
+            data: RData::Ptr(self.instance(service)),
+        }
+    }
+
+    /// Adds the answers for one question, returning whether any matched.
+    fn answer(&self, reply: &mut Builder, name: &str, qtype: u16) -> bool {
+        let wants = |t: u16| qtype == t || qtype == dns::TYPE_ANY;
+        let mut matched = false;
+
+        if dns::name_eq(name, &self.hostname) && wants(dns::TYPE_A) {
+            reply.answer(&self.a(), true);
+            matched = true;
+        }
+        if dns::name_eq(name, SERVICE_ENUMERATION) && wants(dns::TYPE_PTR) {
+            for service in SERVICES {
+                let record = Record {
+                    name: SERVICE_ENUMERATION.into(),
+                    ttl: TTL,
+                    data: RData::Ptr(service.into()),
+                };
+                reply.answer(&record, false);
+            }
+            matched = true;
+        }
+        for service in SERVICES {
+            if dns::name_eq(name, service) && wants(dns::TYPE_PTR) {
+                reply.answer(&self.ptr(service), false);
+                reply.additional(&self.srv(service), true);
+                reply.additional(&self.txt(service), true);
+                reply.additional(&self.a(), true);
+                matched = true;
+            }
+            if dns::name_eq(name, &self.instance(service)) {
+                if wants(dns::TYPE_SRV) {
+                    reply.answer(&self.srv(service), true);
+                    reply.additional(&self.a(), true);
+                    matched = true;
+                }
+                if wants(dns::TYPE_TXT) {
+                    reply.answer(&self.txt(service), true);
+                    matched = true;
+                }
+            }
+        }
+        matched
+    }
+
+    fn announcement(&self) -> Vec<u8> {
+        let mut reply = Builder::new(0, true);
+        reply.answer(&self.a(), true);
+        for service in SERVICES {
+            reply.answer(&self.ptr(service), false);
+            reply.answer(&self.srv(service), true);
+            reply.answer(&self.txt(service), true);
+        }
+        reply.finish()
+    }
+}
+
+/// Answers `<device-name>.local` and advertises the node's services.
+#[task]
+pub async fn mdns_task(stack: Stack<'static>, config: &'static SharedConfig) {
+    stack.wait_config_up().await;
+
+    let (name, leds) = {
+        let config = config.lock().await;
+        (
+            wifi::device_name(&config.get().net),
+            config.get().strip.leds,
+        )
+    };
+    let mut host = Host {
+        hostname: format!("{}.local", name),
+        name,
+        ip: [0; 4],
+        txt: vec![
+            format!("version={}", env!("CARGO_PKG_VERSION")),
+            format!("leds={}", leds),
+        ],
+    };
+
+    if let Err(e) = stack.join_multicast_group(MDNS_ADDRESS) {
+        error!("mdns join error: {:?}", e);
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        error!("mdns bind error: {:?}", e);
+        return;
+    }
+
+    let multicast = IpEndpoint::new(MDNS_ADDRESS.into(), MDNS_PORT);
+    let mut buf = [0u8; 512];
+    loop {
+        let ip = match stack.config_v4() {
+            Some(c) => c.address.address().octets(),
+            None => {
+                stack.wait_config_up().await;
+                continue;
+            }
+        };
+        if ip != host.ip {
+            host.ip = ip;
+            info!("mDNS: announcing {}", host.hostname.as_str());
+            // RFC 6762 asks for at least two announcements a second apart.
+            for _ in 0..2 {
+                socket.send_to(&host.announcement(), multicast).await.ok();
+                Timer::after(Duration::from_secs(1)).await;
+            }
+        }
+
+        let Ok((n, meta)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Some(query) = dns::parse(&buf[..n]) else {
+            continue;
+        };
+        if query.response {
+            continue;
+        }
+
+        // Queries from a port other than 5353 come from simple resolvers
+        // that expect a classic unicast DNS reply.
+        let legacy = meta.endpoint.port != MDNS_PORT;
+        let mut reply = Builder::new(if legacy { query.id } else { 0 }, true);
+        let mut unicast = legacy;
+        let mut matched = false;
+        for q in &query.questions {
+            if legacy {
+                reply.question(&q.name, q.qtype, false);
+            }
+            if host.answer(&mut reply, &q.name, q.qtype) {
+                matched = true;
+                unicast |= q.unicast;
+            }
+        }
+        if !matched {
+            continue;
+        }
+
+        let to = if unicast { meta.endpoint } else { multicast };
+        if let Err(e) = socket.send_to(&reply.finish(), to).await {
+            warn!("mdns send error: {:?}", e);
+        }
+    }
+}
+
+/// Looks `service` up on the local link and returns the endpoint of the
+/// instance with the highest SRV priority, the first to answer on ties.
+pub async fn browse(stack: Stack<'static>, service: &str) -> Option<IpEndpoint> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 2048];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 128];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // Asking from an ephemeral port makes responders reply straight to us.
+    if let Err(e) = socket.bind(0) {
+        error!("mdns bind error: {:?}", e);
+        return None;
+    }
+
+    let id = Instant::now().as_ticks() as u16;
+    let mut query = Builder::new(id, false);
+    query.question(service, dns::TYPE_PTR, true);
+    let multicast = IpEndpoint::new(MDNS_ADDRESS.into(), MDNS_PORT);
+    if let Err(e) = socket.send_to(&query.finish(), multicast).await {
+        warn!("mdns send error: {:?}", e);
+        return None;
+    }
+
+    let deadline = Instant::now() + BROWSE_TIME;
+    let mut best: Option<(u16, IpEndpoint)> = None;
+    let mut buf = [0u8; 512];
+    while let Ok(Ok((n, _))) = with_deadline(deadline, socket.recv_from(&mut buf)).await {
+        let Some(reply) = dns::parse(&buf[..n]) else {
+            continue;
+        };
+        if !reply.response || reply.id != id {
+            continue;
+        }
+        if let Some(found) = instance(&reply.records, service)
+            && best.is_none_or(|(priority, _)| found.0 < priority)
+        {
+            best = Some(found);
+        }
+    }
+    best.map(|(_, endpoint)| endpoint)
+}
+
+/// Follows PTR, SRV and A records of one reply down to an address.
+fn instance(records: &[Record], service: &str) -> Option<(u16, IpEndpoint)> {
+    records
+        .iter()
+        .filter_map(|r| match &r.data {
+            RData::Ptr(name) if dns::name_eq(&r.name, service) => Some(name),
+            _ => None,
+        })
+        .filter_map(|name| {
+            records.iter().find_map(|r| match &r.data {
+                RData::Srv {
+                    priority,
+                    port,
+                    target,
+                    ..
+                } if dns::name_eq(&r.name, name) => Some((*priority, *port, target)),
+                _ => None,
+            })
+        })
+        .filter_map(|(priority, port, target)| {
+            records.iter().find_map(|r| match r.data {
+                RData::A(ip) if dns::name_eq(&r.name, target) => {
+                    Some((priority, IpEndpoint::new(Ipv4Addr::from(ip).into(), port)))
+                }
+                _ => None,
+            })
+        })
+        .min_by_key(|(priority, _)| *priority)
+}
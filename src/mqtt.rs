@@ -1,16 +1,27 @@
+use core::future::pending;
 use core::net::Ipv4Addr;
 
 use core::result::Result::*;
+use alloc::format;
 use alloc::vec::Vec;
-use defmt::{error, info, warn};
 use embassy_executor::task;
-use embassy_net::{Stack, tcp::TcpSocket};
+use embassy_net::{IpEndpoint, Stack, dns::DnsQueryType, tcp::TcpSocket};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::channel::{Channel, Receiver, Sender};
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{Either3, select3};
+use embassy_time::{Duration, Ticker, Timer, WithTimeout};
 use rust_mqtt::client::event::Publish;
 
-use crate::led::{self, Light, Ready};
+use crate::config::{LogSink, MqttConfig, SharedConfig};
+use crate::crash;
+use crate::led::{self, Command, Light, PresetCall, Ready};
+use crate::log::{self, LevelCommand, Limiter, Record};
+use crate::mdns;
+use crate::ota::{self, Chunk, Manifest};
+use crate::telemetry::Telemetry;
+use crate::version;
+use crate::watchdog::{self, Watched};
+use crate::{debug, error, info, warn};
 
 use rust_mqtt::Bytes;
 use rust_mqtt::{
@@ -24,25 +35,38 @@ use rust_mqtt::{
         },
     },
     config::{KeepAlive, SessionExpiryInterval},
-    types::{MqttBinary, MqttString, QoS, TopicName},
+    types::{MqttBinary, MqttString, QoS, TopicFilter, TopicName},
 };
 
 use {esp_backtrace as _, esp_println as _};
 
-type MyMqttClient<'a> = Client<'a, TcpSocket<'a>, BumpBuffer<'a>, 1, 1, 1>;
+type MyMqttClient<'a> = Client<'a, TcpSocket<'a>, BumpBuffer<'a>, 3, 1, 1>;
 
-const REMOTE_ENDPOINT: (Ipv4Addr, u16) = (Ipv4Addr::new(192, 168, 1, 1), 1883);
+const TELEMETRY_PERIOD: Duration = Duration::from_secs(60);
+// Well inside the supervisor's deadline, which an unanswered connect would
+// otherwise run past.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const BROKER_SERVICE: &str = "_mqtt._tcp.local";
 
 #[task]
 pub async fn mqtt_task(
     stack: Stack<'static>,
-    l_sen: &'static Sender<'static, NoopRawMutex, Light, 3>,
-    led_status_channel: &'static Channel<NoopRawMutex, Ready, 3>
+    l_sen: &'static Sender<'static, NoopRawMutex, Command, 3>,
+    led_status_channel: &'static Channel<NoopRawMutex, Ready, 3>,
+    config: &'static SharedConfig,
 ) {
     wait_ip(stack, led_status_channel).await;
 
+    let (mqtt, log) = {
+        let config = config.lock().await;
+        (config.get().mqtt.clone(), config.get().log.clone())
+    };
+
     
 
+    // Carries the build as a retained birth message while connected, and the
+    // will once the connection is lost.
+    let status_topic = format!("{}/status", mqtt.topic);
     let o = ConnectOptions {
         session_expiry_interval: SessionExpiryInterval::Seconds(600),
         clean_start: false,
@@ -50,7 +74,7 @@ pub async fn mqtt_task(
         will: Some(WillOptions {
             will_qos: QoS::ExactlyOnce,
             will_retain: true,
-            will_topic: MqttString::try_from("el_status").unwrap(),
+            will_topic: MqttString::from_slice(&status_topic).unwrap(),
             will_payload: MqttBinary::try_from("el cut down").unwrap(),
             will_delay_interval: 10,
             is_payload_utf8: true,
@@ -63,7 +87,24 @@ pub async fn mqtt_task(
         password: None,
     };
 
-    let topic = unsafe { TopicName::new_unchecked(MqttString::from_slice("el").unwrap()) };
+    let topic = unsafe { TopicName::new_unchecked(MqttString::from_slice(&mqtt.topic).unwrap()) };
+    let telemetry_topic = format!("{}/telemetry", mqtt.topic);
+    let telemetry_topic =
+        unsafe { TopicName::new_unchecked(MqttString::from_slice(&telemetry_topic).unwrap()) };
+    // Updates arrive as a manifest on `<topic>/ota`, then as chunks on
+    // `<topic>/ota/chunk` unless the manifest has a URL.
+    let ota_filter = format!("{}/ota/#", mqtt.topic);
+    let ota_filter =
+        unsafe { TopicFilter::new_unchecked(MqttString::from_slice(&ota_filter).unwrap()) };
+    // Settings changed at runtime, such as `<topic>/config/log`.
+    let config_filter = format!("{}/config/#", mqtt.topic);
+    let config_filter =
+        unsafe { TopicFilter::new_unchecked(MqttString::from_slice(&config_filter).unwrap()) };
+    // The last crash, retained until the next one replaces it.
+    let crash_topic = format!("{}/crash", mqtt.topic);
+    let log_topic = format!("{}/log", mqtt.topic);
+    // Kept across connections, so that reconnecting does not reset it.
+    let mut limiter = matches!(log.sink, LogSink::Mqtt).then(|| Limiter::new(log.rate));
 
     loop {
         let mut rx_buffer = [0u8; 4096];
@@ -73,22 +114,48 @@ pub async fn mqtt_task(
 
         socket.set_timeout(Some(Duration::from_secs(1000)));
 
-        let mut mqtt_buffer = [0u8; 1024];
+        // Holds one received message at a time; update chunks carry up to
+        // 1 KiB of data.
+        let mut mqtt_buffer = [0u8; 2048];
         let mut mqtt_bump = BumpBuffer::new(&mut mqtt_buffer);
 
         Timer::after(Duration::from_secs(1)).await;
 
-        let mut client = Client::<'_, _, _, 1, 1, 1>::new(&mut mqtt_bump);
+        let mut client = Client::<'_, _, _, 3, 1, 1>::new(&mut mqtt_bump);
 
         Timer::after(Duration::from_secs(1)).await;
 
-        connet_tcp_async(&mut socket, led_status_channel).await;
+        connet_tcp_async(stack, &mut socket, &mqtt, led_status_channel).await;
 
 
 
-        mqtt_connect_async(socket, &mut client, &o).await;
+        mqtt_connect_async(socket, &mut client, &o, &mqtt.client_id).await;
 
-        subscribe_n_cofirm_async(topic.clone(), &mut client, l_sen).await;
+        let mut reports = Reports {
+            status: unsafe {
+                TopicName::new_unchecked(MqttString::from_slice(&status_topic).unwrap())
+            },
+            crash: unsafe {
+                TopicName::new_unchecked(MqttString::from_slice(&crash_topic).unwrap())
+            },
+            log: unsafe { TopicName::new_unchecked(MqttString::from_slice(&log_topic).unwrap()) },
+            limiter: limiter.as_mut(),
+        };
+        let telemetry = TelemetrySource {
+            stack,
+            config,
+            topic: telemetry_topic.clone(),
+        };
+        subscribe_n_cofirm_async(
+            topic.clone(),
+            [ota_filter.clone(), config_filter.clone()],
+            &mqtt.topic,
+            &mut reports,
+            &mut client,
+            l_sen,
+            &telemetry,
+        )
+        .await;
 
         // publish_n_confirm_async(l_rec, topic.clone(), &mut client).await;
 
@@ -115,6 +182,7 @@ async fn wait_ip(
 
 ) {
     loop {
+        watchdog::check_in(Watched::Mqtt);
         if stack.is_config_up() {
             break;
         }
@@ -126,16 +194,51 @@ async fn wait_ip(
     }
 }
 
+async fn resolve_broker(stack: Stack<'static>, mqtt: &MqttConfig) -> Option<IpEndpoint> {
+    if mqtt.discover || mqtt.host.is_empty() {
+        match mdns::browse(stack, BROKER_SERVICE).await {
+            Some(endpoint) => {
+                info!("Discovered broker at {}", endpoint);
+                return Some(endpoint);
+            }
+            None => warn!("No broker answered on mDNS"),
+        }
+        if mqtt.host.is_empty() {
+            return None;
+        }
+    }
+    if let Ok(ip) = mqtt.host.parse::<Ipv4Addr>() {
+        return Some(IpEndpoint::new(ip.into(), mqtt.port));
+    }
+    match stack.dns_query(&mqtt.host, DnsQueryType::A).await {
+        Ok(addrs) => addrs.first().map(|ip| IpEndpoint::new(*ip, mqtt.port)),
+        Err(e) => {
+            error!("dns error: {:?}", e);
+            None
+        }
+    }
+}
+
 async fn connet_tcp_async<'a>(
+    stack: Stack<'static>,
     socket: &mut TcpSocket<'a>,
+    mqtt: &MqttConfig,
     led_status_channel: &Channel<NoopRawMutex, Ready, 3>,
 ) {
     info!("connecting...");
     loop {
-        let r = socket.connect(REMOTE_ENDPOINT).await;
-        if let Err(e) = r {
+        watchdog::check_in(Watched::Mqtt);
+        let Some(endpoint) = resolve_broker(stack, mqtt).await else {
+            Timer::after(Duration::from_secs(5)).await;
+            continue;
+        };
+        let r = socket.connect(endpoint).with_timeout(CONNECT_TIMEOUT).await;
+        if let Ok(Err(e)) = r {
             error!("connect error: {:?}", e);
             Timer::after(Duration::from_secs(5)).await;
+        } else if r.is_err() {
+            error!("connect timed out");
+            socket.abort();
         } else {
             info!("TCP connected!");
             let got_tcp = Ready::tcp();
@@ -151,24 +254,134 @@ async fn mqtt_connect_async<'a>(
     socket: TcpSocket<'a>,
     client: &mut MyMqttClient<'a>,
     o: &ConnectOptions<'a>,
+    client_id: &'a str,
 ) {
     let c_info = client
         .connect(
             socket,
             o,
-            Some(MqttString::try_from("rust-mqtt-demo-client").unwrap()),
+            Some(MqttString::try_from(client_id).unwrap()),
         )
         .await;
 
     match c_info {
-        Ok(_) => info!("Mqtt connected"),
+        Ok(_) => {
+            info!("Mqtt connected");
+            ota::confirm();
+        }
         Err(_) => {
             error!("Mqtt not connected");
         }
     };
 }
 
-async fn subscribe_n_cofirm_async<'a>(topic: TopicName<'a>, client: &mut MyMqttClient<'a>, l_sen: &'static Sender<'static, NoopRawMutex, Light, 3>) {
+// Where the node reports on itself, besides telemetry.
+struct Reports<'a> {
+    status: TopicName<'a>,
+    crash: TopicName<'a>,
+    log: TopicName<'a>,
+    // Set when log records go to the broker.
+    limiter: Option<&'a mut Limiter>,
+}
+
+struct TelemetrySource<'a> {
+    stack: Stack<'static>,
+    config: &'static SharedConfig,
+    topic: TopicName<'a>,
+}
+
+async fn publish_telemetry<'a>(
+    client: &mut MyMqttClient<'a>,
+    telemetry: &TelemetrySource<'a>,
+) -> Result<(), MqttError<'a>> {
+    let report = Telemetry::collect(telemetry.stack, telemetry.config).await;
+    let payload = minicbor::to_vec(&report).unwrap();
+    let pub_options = PublicationOptions {
+        retain: false,
+        topic: telemetry.topic.clone(),
+        qos: QoS::AtMostOnce,
+    };
+    client
+        .publish(&pub_options, Bytes::from(payload.as_slice()))
+        .await
+        .map(|_| ())
+}
+
+async fn publish_birth<'a>(
+    client: &mut MyMqttClient<'a>,
+    topic: TopicName<'a>,
+) -> Result<(), MqttError<'a>> {
+    let payload = minicbor::to_vec(version::BUILD).unwrap();
+    let pub_options = PublicationOptions {
+        retain: true,
+        topic,
+        qos: QoS::AtMostOnce,
+    };
+    client
+        .publish(&pub_options, Bytes::from(payload.as_slice()))
+        .await
+        .map(|_| ())
+}
+
+async fn set_log_level(config: &SharedConfig, message: &[u8]) {
+    let Ok(command) = minicbor::decode::<LevelCommand>(message) else {
+        warn!("Bad log level command");
+        return;
+    };
+    let mut config = config.lock().await;
+    // Applies even when it cannot be saved.
+    if let Err(e) = config.update(|c| command.apply(&mut c.log)) {
+        error!("failed to save log levels: {:?}", e);
+    }
+    log::init(&config.get().log);
+}
+
+async fn publish_log<'a>(
+    client: &mut MyMqttClient<'a>,
+    topic: TopicName<'a>,
+    record: &Record,
+) -> Result<(), MqttError<'a>> {
+    let payload = minicbor::to_vec(record).unwrap();
+    let pub_options = PublicationOptions {
+        retain: false,
+        topic,
+        qos: QoS::AtMostOnce,
+    };
+    client
+        .publish(&pub_options, Bytes::from(payload.as_slice()))
+        .await
+        .map(|_| ())
+}
+
+async fn publish_crash<'a>(
+    client: &mut MyMqttClient<'a>,
+    topic: TopicName<'a>,
+) -> Result<(), MqttError<'a>> {
+    let Some(crash) = crash::pending() else {
+        return Ok(());
+    };
+    let payload = minicbor::to_vec(&crash).unwrap();
+    let pub_options = PublicationOptions {
+        retain: true,
+        topic,
+        qos: QoS::AtMostOnce,
+    };
+    client
+        .publish(&pub_options, Bytes::from(payload.as_slice()))
+        .await?;
+    crash::reported();
+    Ok(())
+}
+
+async fn subscribe_n_cofirm_async<'a>(
+    topic: TopicName<'a>,
+    filters: [TopicFilter<'a>; 2],
+    base: &str,
+    reports: &mut Reports<'a>,
+    client: &mut MyMqttClient<'a>,
+    l_sen: &'static Sender<'static, NoopRawMutex, Command, 3>,
+    telemetry: &TelemetrySource<'a>,
+) {
     
     let sub_options = SubscriptionOptions {
         retain_handling: RetainHandling::AlwaysSend,
@@ -188,9 +401,66 @@ async fn subscribe_n_cofirm_async<'a>(topic: TopicName<'a>, client: &mut MyMqttC
         }
     };
 
+    for filter in filters {
+        if let Err(e) = client.subscribe(filter, sub_options).await {
+            error!("Failed to subscribe: {:?}", e);
+            return;
+        }
+    }
+
+    if let Err(e) = publish_birth(client, reports.status.clone()).await {
+        error!("Failed to publish birth message {:?}", e);
+        return;
+    }
+    if let Err(e) = publish_crash(client, reports.crash.clone()).await {
+        error!("Failed to publish crash report {:?}", e);
+        return;
+    }
+    if let Err(e) = publish_telemetry(client, telemetry).await {
+        error!("Failed to publish telemetry {:?}", e);
+        return;
+    }
+    let mut telemetry_ticker = Ticker::every(TELEMETRY_PERIOD);
+    let mut chunked = ota::Chunked::new(telemetry.config);
+
     loop {
-        info!("poll");
-        match client.poll().await {
+        // Messages are handled one at a time, so nothing received earlier is
+        // still borrowed from the buffer.
+        unsafe {
+            client.buffer().reset();
+        }
+        watchdog::check_in(Watched::Mqtt);
+        debug!("poll");
+        let next_log = async {
+            match reports.limiter.as_mut() {
+                Some(limiter) => log::next(limiter).await,
+                None => pending().await,
+            }
+        };
+        // Only waiting for the fixed header is cancel-safe.
+        let header = match select3(client.poll_header(), telemetry_ticker.next(), next_log).await {
+            Either3::First(Ok(header)) => header,
+            Either3::First(Err(e)) => {
+                error!("Failed to poll {:?}", e);
+                break;
+            }
+            Either3::Second(_) => {
+                if let Err(e) = publish_telemetry(client, telemetry).await {
+                    error!("Failed to publish telemetry {:?}", e);
+                    break;
+                }
+                continue;
+            }
+            Either3::Third(record) => {
+                if let Err(e) = publish_log(client, reports.log.clone(), &record).await {
+                    log::requeue(record);
+                    error!("Failed to publish log record {:?}", e);
+                    break;
+                }
+                continue;
+            }
+        };
+        match client.poll_body(header).await {
             Ok(Event::Suback(Suback {
                 packet_identifier,
                 reason_code,
@@ -209,11 +479,32 @@ async fn subscribe_n_cofirm_async<'a>(topic: TopicName<'a>, client: &mut MyMqttC
                 message,
             })) => { 
                 warn!("Event after subscribing: {:?}", identified_qos);
-                let msg = minicbor::decode::<Light>(&message);
+                let topic: &str = topic.as_ref();
+                if let Some(rest) = topic.strip_prefix(base).filter(|rest| !rest.is_empty()) {
+                    // A retained manifest would restart the update on every
+                    // connect.
+                    match rest {
+                        "/ota" if !retain => match minicbor::decode::<Manifest>(&message) {
+                            Ok(manifest) if manifest.url.is_some() => ota::FETCH.signal(manifest),
+                            Ok(manifest) => chunked.manifest(&manifest).await,
+                            Err(_) => warn!("Bad update manifest"),
+                        },
+                        "/ota/chunk" => match minicbor::decode::<Chunk>(&message) {
+                            Ok(chunk) => chunked.chunk(&chunk).await,
+                            Err(_) => warn!("Bad update chunk"),
+                        },
+                        "/config/log" => set_log_level(telemetry.config, &message).await,
+                        _ => {}
+                    }
+                    continue;
+                }
+                let msg = minicbor::decode::<Light>(&message)
+                    .map(Command::Light)
+                    .or_else(|_| minicbor::decode::<PresetCall>(&message).map(Command::from));
                 info!("{:?}", message);
-                if let Ok(l) = msg {    
+                if let Ok(command) = msg {    
                     
-                    l_sen.send(l).await;
+                    l_sen.send(command).await;
                     error!(">>");
                     Timer::after(Duration::from_millis(20)).await;
 
@@ -290,7 +581,7 @@ async fn publish_n_confirm_async<'a>(
 
 async fn poll_async<'a>(
     client: &mut MyMqttClient<'a>,
-    l_sen: &'static Sender<'static, NoopRawMutex, Light, 3>,
+    l_sen: &'static Sender<'static, NoopRawMutex, Command, 3>,
 ) -> Result<(), MqttError<'a>> {
     match client.poll().await {
         Ok(e) => {
@@ -298,7 +589,7 @@ async fn poll_async<'a>(
             if let Event::Publish(publish) = e {
                 let res = minicbor::decode::<Light>(&publish.message);
                 match res {
-                    Ok(light) => l_sen.send(light).await,
+                    Ok(light) => l_sen.send(Command::Light(light)).await,
                     Err(_) => todo!(),
                 }
                 todo!()
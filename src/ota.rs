@@ -0,0 +1,312 @@
+use core::net::Ipv4Addr;
+use core::ptr::addr_of_mut;
+
+use alloc::{format, vec::Vec};
+use embassy_executor::task;
+use embassy_futures::select::{Either, select};
+use embassy_net::{IpEndpoint, Stack, dns::DnsQueryType, tcp::TcpSocket};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use esp_bootloader_esp_idf::{
+    ota::OtaImageState,
+    ota_updater::OtaUpdater,
+    partitions::{self, AppPartitionSubType, PARTITION_TABLE_MAX_LEN},
+};
+use esp_storage::FlashStorage;
+
+use crate::config::SharedConfig;
+use crate::flash::{self, Partition};
+use crate::watchdog::{self, Watched};
+use crate::{error, info, warn};
+
+pub use wink_core::ota::*;
+
+// Hex Ed25519 key that images are signed with, set at build time. Without
+// one, every update is rejected.
+const PUBLIC_KEY: Option<&str> = option_env!("OTA_PUBLIC_KEY");
+// An update that has not reached the broker by then is rolled back.
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(300);
+// Boots an update gets to reach the broker in; each crash or watchdog reset
+// before then uses one up.
+const TRIAL_BOOTS: u32 = 3;
+const MAX_HEAD: usize = 1024;
+
+// Marks the boot count as written by an earlier boot rather than left over
+// from power on.
+const MAGIC: u32 = 0x5452_4941;
+
+// Boots of the update on trial so far. Survives resets but not a power
+// cycle, after which the update gets all its boots again.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut TRIAL: [u32; 2] = [0; 2];
+
+fn with_updater<R>(
+    f: impl FnOnce(&mut OtaUpdater<'_, FlashStorage<'static>>) -> Result<R, partitions::Error>,
+) -> Result<R, Error> {
+    flash::with(|flash| {
+        let mut buf = [0u8; PARTITION_TABLE_MAX_LEN];
+        let mut updater = OtaUpdater::new(flash, &mut buf)?;
+        f(&mut updater)
+    })
+    .map_err(|_| Error::NoSlot)
+}
+
+/// The slot updates are written to: the one not running.
+pub fn inactive_slot() -> Result<Partition, Error> {
+    let label = match with_updater(|u| u.next_partition().map(|(_, slot)| slot))? {
+        AppPartitionSubType::Ota0 => "ota_0",
+        AppPartitionSubType::Ota1 => "ota_1",
+        _ => return Err(Error::NoSlot),
+    };
+    flash::find(label).ok_or(Error::NoSlot)
+}
+
+/// Checks a manifest against the key and the rollback counter, and opens
+/// the inactive slot for its image.
+async fn begin(config: &SharedConfig, manifest: &Manifest) -> Result<Update<Partition>, Error> {
+    let key = PUBLIC_KEY.and_then(from_hex).ok_or(Error::Signature)?;
+    manifest.verify(&key, config.lock().await.get().ota_version)?;
+    Update::new(inactive_slot()?, manifest)
+}
+
+/// Boots the slot just written, on trial until it reaches the broker. The
+/// rollback counter moves up first, so the previous image cannot be sent
+/// again.
+async fn install(config: &SharedConfig, version: u32) -> ! {
+    let mut config = config.lock().await;
+    match with_updater(|u| {
+        u.activate_next_partition()?;
+        u.set_current_ota_state(OtaImageState::New)
+    }) {
+        Ok(_) => {
+            // Only an update that is going to boot keeps older ones out.
+            if let Err(e) = config.update(|c| c.ota_version = version) {
+                error!("failed to save the firmware version: {:?}", e);
+            }
+            info!("Update installed, restarting");
+        }
+        Err(e) => error!("failed to activate update: {:?}", e),
+    }
+    set_trial_boots(0);
+    esp_hal::system::software_reset()
+}
+
+/// Whether this boot runs an update that has not been confirmed yet. Such
+/// boots are counted, and an update that crashes or trips the watchdog
+/// `TRIAL_BOOTS` times before it is confirmed is rolled back here. Call it
+/// before anything else that could crash.
+pub fn start_trial() -> bool {
+    let on_trial = matches!(
+        with_updater(|u| u.current_ota_state()),
+        Ok(OtaImageState::New | OtaImageState::PendingVerify)
+    );
+    if !on_trial {
+        set_trial_boots(0);
+        return false;
+    }
+    let boots = trial_boots() + 1;
+    if boots > TRIAL_BOOTS {
+        warn!("Update failed to start {} times, rolling back", TRIAL_BOOTS);
+        roll_back();
+    }
+    set_trial_boots(boots);
+    true
+}
+
+fn trial_boots() -> u32 {
+    match unsafe { addr_of_mut!(TRIAL).read_volatile() } {
+        [MAGIC, boots] => boots,
+        _ => 0,
+    }
+}
+
+fn set_trial_boots(boots: u32) {
+    unsafe { addr_of_mut!(TRIAL).write_volatile([MAGIC, boots]) };
+}
+
+/// Marks the running update as bad and boots the previous image.
+fn roll_back() -> ! {
+    set_trial_boots(0);
+    if let Err(e) = with_updater(|u| {
+        u.set_current_ota_state(OtaImageState::Invalid)?;
+        u.activate_next_partition()
+    }) {
+        error!("failed to roll back: {:?}", e);
+    }
+    esp_hal::system::software_reset()
+}
+
+static CONFIRMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Marks the running image as good; called once the broker is reached.
+pub fn confirm() {
+    CONFIRMED.signal(());
+}
+
+/// Keeps an update only if it is confirmed in time, and otherwise goes back
+/// to the previous image.
+#[task]
+pub async fn trial_task() {
+    info!("Running an update on trial");
+    match select(CONFIRMED.wait(), Timer::after(TRIAL_TIMEOUT)).await {
+        Either::First(_) => {
+            set_trial_boots(0);
+            match with_updater(|u| u.set_current_ota_state(OtaImageState::Valid)) {
+                Ok(_) => info!("Update confirmed"),
+                Err(e) => error!("failed to confirm update: {:?}", e),
+            }
+        }
+        Either::Second(_) => {
+            warn!("Update never reached the broker, rolling back");
+            roll_back();
+        }
+    }
+}
+
+/// Assembles an image sent over MQTT: a manifest, then chunks.
+pub struct Chunked {
+    config: &'static SharedConfig,
+    // The update and the version it brings.
+    update: Option<(Update<Partition>, u32)>,
+}
+
+impl Chunked {
+    pub fn new(config: &'static SharedConfig) -> Self {
+        Chunked {
+            config,
+            update: None,
+        }
+    }
+
+    pub async fn manifest(&mut self, manifest: &Manifest) {
+        self.update = match begin(self.config, manifest).await {
+            Ok(update) => {
+                info!(
+                    "Receiving update {} of {} bytes",
+                    manifest.version, manifest.size
+                );
+                Some((update, manifest.version))
+            }
+            Err(e) => {
+                error!("cannot start update: {:?}", e);
+                None
+            }
+        };
+    }
+
+    /// Writes a chunk, installing the image once it is complete.
+    pub async fn chunk(&mut self, chunk: &Chunk<'_>) {
+        let Some((update, version)) = &mut self.update else {
+            return;
+        };
+        if let Err(e) = update.write(chunk.offset, chunk.data) {
+            error!("update aborted: {:?}", e);
+            self.update = None;
+            return;
+        }
+        if update.is_complete() {
+            let version = *version;
+            match self.update.take().unwrap().0.finish() {
+                Ok(_) => install(self.config, version).await,
+                Err(e) => error!("update rejected: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Set to have `ota_task` download an image.
+pub static FETCH: Signal<CriticalSectionRawMutex, Manifest> = Signal::new();
+
+/// Downloads images announced with a URL.
+#[task]
+pub async fn ota_task(stack: Stack<'static>, config: &'static SharedConfig) {
+    let mut rx_buffer = [0u8; 4096];
+    let mut tx_buffer = [0u8; 512];
+    loop {
+        let manifest = watchdog::idle(Watched::Ota, FETCH.wait()).await;
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        match fetch(stack, &mut socket, config, &manifest).await {
+            Ok(_) => install(config, manifest.version).await,
+            Err(e) => error!("update failed: {:?}", e),
+        }
+        socket.abort();
+    }
+}
+
+async fn fetch(
+    stack: Stack<'static>,
+    socket: &mut TcpSocket<'_>,
+    config: &SharedConfig,
+    manifest: &Manifest,
+) -> Result<(), Error> {
+    let url = manifest.url.as_deref().ok_or(Error::Manifest)?;
+    let (host, port, path) = parse_url(url).ok_or(Error::Manifest)?;
+    let ip = match host.parse::<Ipv4Addr>() {
+        Ok(ip) => ip.into(),
+        Err(_) => *stack
+            .dns_query(host, DnsQueryType::A)
+            .await
+            .map_err(|_| Error::Network)?
+            .first()
+            .ok_or(Error::Network)?,
+    };
+    let mut update = begin(config, manifest).await?;
+    info!(
+        "Downloading update {} of {} bytes",
+        manifest.version, manifest.size
+    );
+
+    socket
+        .connect(IpEndpoint::new(ip, port))
+        .await
+        .map_err(|_| Error::Network)?;
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host);
+    socket
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|_| Error::Network)?;
+
+    // HTTP/1.0 ends the body by closing the connection.
+    let mut head = Vec::new();
+    let mut in_body = false;
+    let mut buf = [0u8; 1024];
+    loop {
+        watchdog::check_in(Watched::Ota);
+        let n = socket.read(&mut buf).await.map_err(|_| Error::Network)?;
+        if n == 0 {
+            break;
+        }
+        if in_body {
+            update.write(update.received(), &buf[..n])?;
+            continue;
+        }
+        head.extend_from_slice(&buf[..n]);
+        let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if head.len() > MAX_HEAD {
+                return Err(Error::Http);
+            }
+            continue;
+        };
+        if head.split(|b| *b == b' ').nth(1) != Some(b"200") {
+            return Err(Error::Http);
+        }
+        update.write(0, &head[end + 4..])?;
+        in_body = true;
+    }
+    update.finish()
+}
+
+/// Splits `http://host[:port]/path`.
+fn parse_url(url: &str) -> Option<(&str, u16, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    match authority.split_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?, path)),
+        None => Some((authority, 80, path)),
+    }
+}
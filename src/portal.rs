@@ -0,0 +1,353 @@
+use core::cell::Cell;
+use core::net::Ipv4Addr;
+
+use alloc::{format, string::String, vec::Vec};
+use embassy_executor::task;
+use embassy_futures::join::join3;
+use embassy_net::{
+    IpEndpoint, Stack,
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::config::{Config, MAX_NETWORKS, Network, SharedConfig};
+use crate::http;
+use crate::{error, info, warn};
+
+pub const AP_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+
+/// Raised by the `connection` task once the access point is up.
+pub static START: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// When the form was last asked for.
+static LAST_USED: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// Whether someone asked for the form within the last `period`.
+pub fn used_within(period: Duration) -> bool {
+    LAST_USED
+        .lock(Cell::get)
+        .is_some_and(|t| t.elapsed() < period)
+}
+
+/// Serves the form on the access point. The servers are left running when
+/// the `connection` task takes the access point down again.
+#[task]
+pub async fn portal_task(stack: Stack<'static>, config: &'static SharedConfig) {
+    START.wait().await;
+    info!("Provisioning portal on {}", AP_ADDRESS);
+    join3(
+        serve_http(stack, config),
+        serve_dhcp(stack),
+        serve_dns(stack),
+    )
+    .await;
+}
+
+async fn serve_http(stack: Stack<'static>, config: &'static SharedConfig) {
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 2048];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        if let Err(e) = socket.accept(80).await {
+            error!("accept error: {:?}", e);
+            continue;
+        }
+
+        let request = match http::read_request(&mut socket).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("bad request: {:?}", e);
+                socket.abort();
+                continue;
+            }
+        };
+        LAST_USED.lock(|t| t.set(Some(Instant::now())));
+
+        if request.method == "POST" && request.path == "/save" {
+            let mut store = config.lock().await;
+            let mut new = store.get().clone();
+            apply_form(&mut new, &request.body);
+            let res = new.validate().map(|_| store.update(|c| *c = new));
+            drop(store);
+            match res {
+                Ok(Ok(_)) => {
+                    http::respond(&mut socket, "200 OK", "text/html", SAVED.as_bytes())
+                        .await
+                        .ok();
+                    socket.close();
+                    info!("Configuration saved, rebooting");
+                    Timer::after(Duration::from_secs(1)).await;
+                    esp_hal::system::software_reset();
+                }
+                Ok(Err(e)) => {
+                    error!("failed to save config: {:?}", e);
+                    http::respond(&mut socket, "500 Internal Server Error", "text/plain", b"")
+                        .await
+                        .ok();
+                }
+                Err(e) => {
+                    warn!("form rejected: {:?}", e);
+                    http::respond(
+                        &mut socket,
+                        "400 Bad Request",
+                        "text/html",
+                        INVALID.as_bytes(),
+                    )
+                    .await
+                    .ok();
+                }
+            }
+        } else {
+            let page = form(config.lock().await.get());
+            http::respond(&mut socket, "200 OK", "text/html", page.as_bytes())
+                .await
+                .ok();
+        }
+        socket.close();
+        Timer::after(Duration::from_millis(50)).await;
+        socket.abort();
+    }
+}
+
+fn apply_form(config: &mut Config, body: &[u8]) {
+    let mut network = Network {
+        ssid: String::new(),
+        password: String::new(),
+    };
+    for (name, value) in http::form_fields(body) {
+        match name.as_str() {
+            "ssid" => network.ssid = value,
+            "password" => network.password = value,
+            "broker" => match value.rsplit_once(':').map(|(h, p)| (h, p.parse())) {
+                Some((host, Ok(port))) => {
+                    config.mqtt.host = host.into();
+                    config.mqtt.port = port;
+                }
+                _ => config.mqtt.host = value,
+            },
+            "leds" => {
+                if let Ok(leds) = value.parse() {
+                    config.strip.leds = leds;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !network.ssid.is_empty() {
+        // The network entered last becomes the preferred one, and the one
+        // entered longest ago goes when the list is full. The form never
+        // shows a stored password, so an empty one keeps it.
+        if let Some(i) = config
+            .wifi
+            .networks
+            .iter()
+            .position(|n| n.ssid == network.ssid)
+        {
+            let known = config.wifi.networks.remove(i);
+            if network.password.is_empty() {
+                network.password = known.password;
+            }
+        }
+        config.wifi.networks.insert(0, network);
+        config.wifi.networks.truncate(MAX_NETWORKS);
+    }
+}
+
+fn form(config: &Config) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+        <title>wink setup</title></head><body><h1>wink setup</h1>\
+        <form method=\"post\" action=\"/save\">\
+        <p>SSID<br><input name=\"ssid\" value=\"{}\"></p>\
+        <p>Password<br><input name=\"password\" type=\"password\"></p>\
+        <p>Broker (empty to discover)<br><input name=\"broker\" value=\"{}:{}\"></p>\
+        <p>LEDs<br><input name=\"leds\" type=\"number\" value=\"{}\"></p>\
+        <p><button>Save</button></p></form></body></html>",
+        escape(
+            config
+                .wifi
+                .networks
+                .first()
+                .map(|n| n.ssid.as_str())
+                .unwrap_or_default()
+        ),
+        escape(&config.mqtt.host),
+        config.mqtt.port,
+        config.strip.leds
+    )
+}
+
+const SAVED: &str = "<!DOCTYPE html><html><body><h1>Saved</h1>\
+    <p>The device is restarting.</p></body></html>";
+
+const INVALID: &str = "<!DOCTYPE html><html><body><h1>Not saved</h1>\
+    <p>The SSID or password is too long, or the strip has more LEDs than the device \
+    drives.</p></body></html>";
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const FIRST_LEASE: u8 = 100;
+const MAX_LEASES: usize = 50;
+
+/// Just enough of a DHCP server to hand out addresses on the access point.
+async fn serve_dhcp(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(DHCP_SERVER_PORT) {
+        error!("dhcp bind error: {:?}", e);
+        return;
+    }
+
+    let mut leases: Vec<[u8; 6]> = Vec::new();
+    let mut buf = [0u8; 576];
+    loop {
+        let Ok((n, _)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let packet = &buf[..n];
+        if n < 240 || packet[0] != 1 || packet[236..240] != DHCP_MAGIC {
+            continue;
+        }
+        let reply_type = match dhcp_message_type(&packet[240..]) {
+            Some(DHCP_DISCOVER) => DHCP_OFFER,
+            Some(DHCP_REQUEST) => DHCP_ACK,
+            _ => continue,
+        };
+
+        let mac: [u8; 6] = packet[28..34].try_into().unwrap();
+        let index = match leases.iter().position(|m| *m == mac) {
+            Some(i) => i,
+            None => {
+                if leases.len() == MAX_LEASES {
+                    leases.remove(0);
+                }
+                leases.push(mac);
+                leases.len() - 1
+            }
+        };
+        let [a, b, c, _] = AP_ADDRESS.octets();
+        let client = [a, b, c, FIRST_LEASE + index as u8];
+
+        let mut reply = Vec::with_capacity(300);
+        reply.extend_from_slice(&[2, 1, 6, 0]);
+        reply.extend_from_slice(&packet[4..12]); // xid, secs, flags
+        reply.extend_from_slice(&[0; 4]); // ciaddr
+        reply.extend_from_slice(&client); // yiaddr
+        reply.extend_from_slice(&AP_ADDRESS.octets()); // siaddr
+        reply.extend_from_slice(&packet[24..236]); // giaddr, chaddr, sname, file
+        reply.extend_from_slice(&DHCP_MAGIC);
+        reply.extend_from_slice(&[53, 1, reply_type]);
+        reply.extend_from_slice(&[54, 4]);
+        reply.extend_from_slice(&AP_ADDRESS.octets());
+        reply.extend_from_slice(&[51, 4, 0, 0, 0x0e, 0x10]); // one hour
+        reply.extend_from_slice(&[1, 4, 255, 255, 255, 0]);
+        reply.extend_from_slice(&[3, 4]);
+        reply.extend_from_slice(&AP_ADDRESS.octets());
+        reply.extend_from_slice(&[6, 4]);
+        reply.extend_from_slice(&AP_ADDRESS.octets());
+        reply.push(255);
+
+        let to = IpEndpoint::new(Ipv4Addr::BROADCAST.into(), DHCP_CLIENT_PORT);
+        if let Err(e) = socket.send_to(&reply, to).await {
+            warn!("dhcp send error: {:?}", e);
+        }
+    }
+}
+
+fn dhcp_message_type(mut options: &[u8]) -> Option<u8> {
+    while let [code, rest @ ..] = options {
+        match code {
+            0 => options = rest,
+            255 => return None,
+            _ => {
+                let (&len, rest) = rest.split_first()?;
+                let value = rest.get(..len as usize)?;
+                if *code == 53 {
+                    return value.first().copied();
+                }
+                options = &rest[len as usize..];
+            }
+        }
+    }
+    None
+}
+
+/// Answers every A query with the portal address so that phones and laptops
+/// pop up their captive-portal login window.
+async fn serve_dns(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(53) {
+        error!("dns bind error: {:?}", e);
+        return;
+    }
+
+    let mut buf = [0u8; 512];
+    loop {
+        let Ok((n, meta)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        // Header plus the single question this is meant to answer.
+        if n < 12 || buf[2] & 0x80 != 0 || buf[4..6] != [0, 1] {
+            continue;
+        }
+        let Some(name_len) = buf[12..n].iter().position(|b| *b == 0) else {
+            continue;
+        };
+        let question_end = 12 + name_len + 1 + 4;
+        if question_end > n {
+            continue;
+        }
+        let is_a = buf[question_end - 4..question_end] == [0, 1, 0, 1];
+
+        let mut reply = Vec::with_capacity(question_end + 16);
+        reply.extend_from_slice(&buf[..2]);
+        reply.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+        reply.extend_from_slice(&buf[12..question_end]);
+        if is_a {
+            reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            reply.extend_from_slice(&AP_ADDRESS.octets());
+        }
+        if let Err(e) = socket.send_to(&reply, meta.endpoint).await {
+            warn!("dns send error: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::{AnyPin, Level, Output, OutputConfig};
+use esp_radio::wifi::PowerSaveMode;
+use minicbor::Encode;
+use smart_leds_trait::RGB8;
+
+use crate::config::{CurrentLimit, RelayConfig, WifiPowerSave};
+use crate::{info, warn};
+
+pub use wink_core::power::*;
+
+// GPIOs free on the ESP32-C3 boards we use: 8 drives the strip, 11 to 17
+// belong to the flash, 18 and 19 to USB and 20 and 21 to UART0. 2 and 9 are
+// strapping pins, which a relay could pull the wrong way at reset.
+const FREE_PINS: [u8; 8] = [0, 1, 3, 4, 5, 6, 7, 10];
+
+/// The estimated draw of the last frame, for telemetry.
+#[derive(Clone, Copy, Encode)]
+#[cbor(map)]
+pub struct Draw {
+    #[n(0)]
+    pub ma: u32,
+    // Brightness the frame was written at.
+    #[n(1)]
+    pub brightness: u8,
+    // Whether that is below the one asked for, to stay within the budget.
+    #[n(2)]
+    pub limited: bool,
+}
+
+static DRAW: Mutex<CriticalSectionRawMutex, Cell<Option<Draw>>> = Mutex::new(Cell::new(None));
+
+/// The draw of the last frame, while a limit is configured.
+pub fn draw() -> Option<Draw> {
+    DRAW.lock(Cell::get)
+}
+
+/// The brightness to write `pixels` at under the limit, if there is one.
+pub fn limit_frame(pixels: &[RGB8], brightness: u8, limit: Option<&CurrentLimit>) -> u8 {
+    let Some(limit) = limit else {
+        return brightness;
+    };
+    let applied = limit_brightness(pixels, brightness, limit);
+    DRAW.lock(|d| {
+        d.set(Some(Draw {
+            ma: estimate_ma(pixels, applied, limit),
+            brightness: applied,
+            limited: applied < brightness,
+        }))
+    });
+    applied
+}
+
+/// The radio's power saving mode for the configured one.
+pub fn wifi_mode(mode: WifiPowerSave) -> PowerSaveMode {
+    match mode {
+        WifiPowerSave::None => PowerSaveMode::None,
+        WifiPowerSave::Minimum => PowerSaveMode::Minimum,
+        WifiPowerSave::Maximum => PowerSaveMode::Maximum,
+    }
+}
+
+/// Switches the strip's supply off while it shows nothing.
+pub struct Relay {
+    pin: Option<Output<'static>>,
+    on_level: Level,
+    on: bool,
+    // Since when the frames written have all been black.
+    black_since: Option<Instant>,
+    off_after: Duration,
+    settle: Duration,
+}
+
+impl Relay {
+    /// The relay on the configured pin, or one that does nothing.
+    pub fn new(config: Option<&RelayConfig>) -> Self {
+        let mut relay = Relay {
+            pin: None,
+            on_level: Level::High,
+            on: true,
+            black_since: None,
+            off_after: Duration::MAX,
+            settle: Duration::from_ticks(0),
+        };
+        let Some(config) = config else {
+            return relay;
+        };
+        if !FREE_PINS.contains(&config.pin) {
+            warn!("GPIO{} cannot drive the relay", config.pin);
+            return relay;
+        }
+        let on_level = if config.active_low {
+            Level::Low
+        } else {
+            Level::High
+        };
+        // SAFETY: the pin is checked against those the firmware uses itself.
+        let pin = unsafe { AnyPin::steal(config.pin) };
+        // Off until there is something to show.
+        relay.pin = Some(Output::new(pin, !on_level, OutputConfig::default()));
+        relay.on_level = on_level;
+        relay.on = false;
+        relay.off_after = Duration::from_secs(config.off_after_s as u64);
+        relay.settle = Duration::from_millis(config.settle_ms as u64);
+        info!("Strip power on GPIO{}", config.pin);
+        relay
+    }
+
+    /// Call before writing a frame. Powers the strip up and waits for it to
+    /// settle unless the frame is black, and tells whether to write it; black
+    /// frames are not sent to a strip that is off, which the data line would
+    /// otherwise partly power.
+    pub async fn frame(&mut self, black: bool) -> bool {
+        if !black {
+            self.black_since = None;
+            if !self.on {
+                self.switch(true);
+                Timer::after(self.settle).await;
+            }
+            return true;
+        }
+        self.black_since.get_or_insert_with(Instant::now);
+        self.on
+    }
+
+    /// Powers the strip down once it has been black for long enough.
+    pub fn poll(&mut self) {
+        if self.on
+            && self
+                .black_since
+                .is_some_and(|t| t.elapsed() >= self.off_after)
+        {
+            self.switch(false);
+        }
+    }
+
+    fn switch(&mut self, on: bool) {
+        let Some(pin) = self.pin.as_mut() else {
+            return;
+        };
+        info!("Strip power {}", if on { "on" } else { "off" });
+        pin.set_level(if on { self.on_level } else { !self.on_level });
+        self.on = on;
+    }
+}
@@ -0,0 +1,170 @@
+use embassy_executor::task;
+use embassy_futures::select::{Either, select};
+use embassy_net::{
+    Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::Duration;
+use smart_leds_trait::RGB8;
+
+use crate::config::SharedConfig;
+use crate::led::{LEDS, REALTIME, Realtime};
+use crate::watchdog::{self, Watched};
+use crate::{error, info};
+
+const DDP_PORT: u16 = 4048;
+const WLED_PORT: u16 = 21324;
+
+const DDP_VERSION_MASK: u8 = 0xc0;
+const DDP_VERSION_1: u8 = 0x40;
+const DDP_TIMECODE: u8 = 0x10;
+const DDP_QUERY: u8 = 0x02;
+const DDP_PUSH: u8 = 0x01;
+const DDP_ID_DISPLAY: u8 = 1;
+// Data type bits telling RGBW apart from plain RGB.
+const DDP_TYPE_MASK: u8 = 0x38;
+const DDP_TYPE_RGBW: u8 = 0x18;
+
+const WLED_WARLS: u8 = 1;
+const WLED_DRGB: u8 = 2;
+const WLED_DRGBW: u8 = 3;
+const WLED_DNRGB: u8 = 4;
+// A WLED timeout byte of 255 keeps the realtime data up for good, while 0
+// ends it right away.
+const WLED_FOREVER: u8 = 255;
+
+/// Accepts DDP and the WLED UDP realtime protocols and hands the pixels to
+/// the renderer.
+#[task]
+pub async fn realtime_task(stack: Stack<'static>, config: &'static SharedConfig) {
+    let (timeout, leds) = {
+        let config = config.lock().await;
+        (
+            Duration::from_millis(config.get().realtime.timeout_ms as u64),
+            (config.get().strip.leds as usize).min(LEDS),
+        )
+    };
+    let mut frame: heapless::Vec<RGB8, LEDS> = heapless::Vec::new();
+    frame.resize(leds, RGB8::default()).ok();
+
+    let mut ddp_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut ddp_rx_buffer = [0u8; 2048];
+    let mut ddp_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut ddp_tx_buffer = [0u8; 16];
+    let mut ddp = UdpSocket::new(
+        stack,
+        &mut ddp_rx_meta,
+        &mut ddp_rx_buffer,
+        &mut ddp_tx_meta,
+        &mut ddp_tx_buffer,
+    );
+    let mut wled_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut wled_rx_buffer = [0u8; 2048];
+    let mut wled_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut wled_tx_buffer = [0u8; 16];
+    let mut wled = UdpSocket::new(
+        stack,
+        &mut wled_rx_meta,
+        &mut wled_rx_buffer,
+        &mut wled_tx_meta,
+        &mut wled_tx_buffer,
+    );
+    if let Err(e) = ddp.bind(DDP_PORT) {
+        error!("DDP bind error: {:?}", e);
+        watchdog::check_out(Watched::Realtime);
+        return;
+    }
+    if let Err(e) = wled.bind(WLED_PORT) {
+        error!("WLED bind error: {:?}", e);
+        watchdog::check_out(Watched::Realtime);
+        return;
+    }
+    info!("Realtime UDP: DDP on {}, WLED on {}", DDP_PORT, WLED_PORT);
+
+    let mut ddp_buf = [0u8; 1500];
+    let mut wled_buf = [0u8; 1500];
+    loop {
+        let received = select(ddp.recv_from(&mut ddp_buf), wled.recv_from(&mut wled_buf));
+        let shown = match watchdog::idle(Watched::Realtime, received).await {
+            Either::First(Ok((n, _))) => ddp_packet(&ddp_buf[..n], &mut frame).then_some(timeout),
+            Either::Second(Ok((n, _))) => wled_packet(&wled_buf[..n], &mut frame),
+            _ => continue,
+        };
+        if let Some(timeout) = shown {
+            REALTIME.signal(Realtime {
+                pixels: frame.clone(),
+                timeout,
+            });
+        }
+    }
+}
+
+/// Copies DDP pixel data into `frame`, returning whether the packet asks for
+/// the frame to be shown.
+fn ddp_packet(packet: &[u8], frame: &mut [RGB8]) -> bool {
+    let [flags, _sequence, data_type, id, ..] = *packet else {
+        return false;
+    };
+    if flags & DDP_VERSION_MASK != DDP_VERSION_1 || flags & DDP_QUERY != 0 || id != DDP_ID_DISPLAY {
+        return false;
+    }
+    let Some(header) = packet.get(4..10) else {
+        return false;
+    };
+    let offset = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let start = if flags & DDP_TIMECODE != 0 { 14 } else { 10 };
+    let Some(data) = packet.get(start..start + length) else {
+        return false;
+    };
+
+    // The offset counts bytes, so it can only be mapped for whole pixels.
+    let width = if data_type & DDP_TYPE_MASK == DDP_TYPE_RGBW {
+        4
+    } else {
+        3
+    };
+    if offset.is_multiple_of(width) {
+        copy_pixels(frame, offset / width, data, width);
+    }
+    flags & DDP_PUSH != 0
+}
+
+/// Applies a WLED realtime packet to `frame` and returns how long to show it.
+fn wled_packet(packet: &[u8], frame: &mut [RGB8]) -> Option<Duration> {
+    let [protocol, seconds, data @ ..] = packet else {
+        return None;
+    };
+    match *protocol {
+        WLED_WARLS => {
+            for [index, r, g, b] in data.as_chunks::<4>().0 {
+                if let Some(pixel) = frame.get_mut(*index as usize) {
+                    *pixel = RGB8::new(*r, *g, *b);
+                }
+            }
+        }
+        WLED_DRGB => copy_pixels(frame, 0, data, 3),
+        WLED_DRGBW => copy_pixels(frame, 0, data, 4),
+        WLED_DNRGB => {
+            let [hi, lo, data @ ..] = data else {
+                return None;
+            };
+            copy_pixels(frame, u16::from_be_bytes([*hi, *lo]) as usize, data, 3);
+        }
+        _ => return None,
+    }
+    Some(match *seconds {
+        WLED_FOREVER => Duration::MAX,
+        s => Duration::from_secs(s as u64),
+    })
+}
+
+/// Writes consecutive pixels of `width` bytes, dropping any white channel.
+fn copy_pixels(frame: &mut [RGB8], first: usize, data: &[u8], width: usize) {
+    let Some(pixels) = frame.get_mut(first..) else {
+        return;
+    };
+    for (pixel, rgb) in pixels.iter_mut().zip(data.chunks_exact(width)) {
+        *pixel = RGB8::new(rgb[0], rgb[1], rgb[2]);
+    }
+}
@@ -0,0 +1,61 @@
+use embassy_executor::task;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Sender};
+use embassy_time::{Duration, Ticker};
+
+use crate::clock;
+use crate::config::{Action, Schedule, SharedConfig, TimeConfig};
+use crate::info;
+use crate::led::Command;
+use crate::watchdog::{self, Watched};
+
+pub use wink_core::schedule::*;
+
+const POLL_PERIOD: Duration = Duration::from_secs(10);
+
+/// The SNTP set wall clock in the configured zone.
+pub struct WallClock<'a>(pub &'a TimeConfig);
+
+impl Clock for WallClock<'_> {
+    fn utc(&self) -> Option<i64> {
+        Some(clock::utc()? as i64)
+    }
+
+    fn to_local(&self, utc: i64) -> i64 {
+        clock::to_local(self.0, utc)
+    }
+}
+
+fn command(schedule: &Schedule) -> Command {
+    let over = Duration::from_secs(schedule.fade_s as u64);
+    let (on, effect) = match &schedule.action {
+        Action::On => (true, None),
+        Action::Off => (false, None),
+        Action::Effect(effect) => (true, Some(effect.clone())),
+        Action::Preset(id) => return Command::Preset { id: *id, over },
+    };
+    Command::Fade { on, effect, over }
+}
+
+/// Runs the configured schedules off the wall clock, independently of the
+/// network once the time has been set.
+#[task]
+pub async fn schedule_task(
+    commands: &'static Sender<'static, NoopRawMutex, Command, 3>,
+    config: &'static SharedConfig,
+) {
+    let mut scheduler = Scheduler::default();
+    let mut ticker = Ticker::every(POLL_PERIOD);
+    loop {
+        watchdog::check_in(Watched::Schedule);
+        ticker.next().await;
+        // Read afresh so that edits over the API apply without a restart.
+        let (zone, schedules) = {
+            let config = config.lock().await;
+            (config.get().time.clone(), config.get().schedules.clone())
+        };
+        for schedule in scheduler.poll(&WallClock(&zone), zone.location.as_ref(), &schedules) {
+            info!("Schedule: {} {=i16} min", schedule.anchor, schedule.minute);
+            commands.send(command(schedule)).await;
+        }
+    }
+}
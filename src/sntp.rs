@@ -0,0 +1,131 @@
+use core::net::Ipv4Addr;
+
+use embassy_executor::task;
+use embassy_net::{
+    IpEndpoint, Stack,
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+
+use crate::clock;
+use crate::config::SharedConfig;
+use crate::{error, info, warn};
+
+const NTP_PORT: u16 = 123;
+// Seconds from 1900, the NTP epoch, to 1970.
+const UNIX_OFFSET: u64 = 2_208_988_800;
+// Version 4, client mode.
+const REQUEST_FLAGS: u8 = 0x23;
+const MODE_SERVER: u8 = 4;
+
+const SYNC_PERIOD: Duration = Duration::from_secs(3600);
+const RETRY_PERIOD: Duration = Duration::from_secs(30);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Keeps the wall clock set from the configured SNTP servers.
+#[task]
+pub async fn sntp_task(stack: Stack<'static>, config: &'static SharedConfig) {
+    let zone = config.lock().await.get().time.clone();
+    if zone.servers.is_empty() {
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 128];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0u8; 128];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(0) {
+        error!("sntp bind error: {:?}", e);
+        return;
+    }
+
+    loop {
+        stack.wait_config_up().await;
+        let mut synced = false;
+        for server in &zone.servers {
+            let Some(endpoint) = resolve(stack, server).await else {
+                continue;
+            };
+            match query(&mut socket, endpoint).await {
+                Some((at, unix_micros)) => {
+                    clock::set_utc(at, unix_micros);
+                    if let Some(local) = clock::local(&zone) {
+                        info!("SNTP: time set from {}: {}", server.as_str(), local);
+                    }
+                    synced = true;
+                    break;
+                }
+                None => warn!("SNTP: no answer from {}", server.as_str()),
+            }
+        }
+        Timer::after(if synced { SYNC_PERIOD } else { RETRY_PERIOD }).await;
+    }
+}
+
+async fn resolve(stack: Stack<'static>, server: &str) -> Option<IpEndpoint> {
+    if let Ok(ip) = server.parse::<Ipv4Addr>() {
+        return Some(IpEndpoint::new(ip.into(), NTP_PORT));
+    }
+    match stack.dns_query(server, DnsQueryType::A).await {
+        Ok(addrs) => addrs.first().map(|ip| IpEndpoint::new(*ip, NTP_PORT)),
+        Err(e) => {
+            warn!("SNTP: cannot resolve {}: {:?}", server, e);
+            None
+        }
+    }
+}
+
+/// Asks one server for the time, returning the Unix time in microseconds
+/// at the returned local instant.
+async fn query(socket: &mut UdpSocket<'_>, server: IpEndpoint) -> Option<(Instant, u64)> {
+    let mut request = [0u8; 48];
+    request[0] = REQUEST_FLAGS;
+    let t0 = Instant::now();
+    socket.send_to(&request, server).await.ok()?;
+
+    let mut reply = [0u8; 48];
+    let deadline = t0 + REPLY_TIMEOUT;
+    loop {
+        let (n, meta) = socket
+            .recv_from(&mut reply)
+            .with_deadline(deadline)
+            .await
+            .ok()?
+            .ok()?;
+        let t3 = Instant::now();
+        if n < 48 || meta.endpoint != server {
+            continue;
+        }
+        // Leap indicator 3 and stratum 0 both mean the server is not synced.
+        if reply[0] & 0x07 != MODE_SERVER || reply[0] >> 6 == 3 || reply[1] == 0 {
+            return None;
+        }
+
+        let t1 = timestamp(&reply[32..40]);
+        let t2 = timestamp(&reply[40..48]);
+        // Server time at t3, assuming the reply took half the round trip
+        // spent outside the server.
+        let round_trip = t3.saturating_duration_since(t0).as_micros();
+        let delay = round_trip.saturating_sub(t2.saturating_sub(t1));
+        return Some((t3, t2 + delay / 2));
+    }
+}
+
+/// Converts an NTP timestamp to Unix microseconds.
+fn timestamp(b: &[u8]) -> u64 {
+    let mut seconds = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u64;
+    let fraction = u32::from_be_bytes([b[4], b[5], b[6], b[7]]) as u64;
+    // Timestamps wrap in 2036; anything before 1970 is in the next era.
+    if seconds < UNIX_OFFSET {
+        seconds += 1 << 32;
+    }
+    (seconds - UNIX_OFFSET) * 1_000_000 + ((fraction * 1_000_000) >> 32)
+}
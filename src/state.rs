@@ -0,0 +1,52 @@
+use embassy_executor::task;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+
+use crate::config::Store;
+use crate::flash::Partition;
+use crate::led::State;
+use crate::watchdog::{self, Watched};
+use crate::{error, info, warn};
+
+/// Bump when `State` changes shape; older records are then ignored.
+const VERSION: u16 = 1;
+// Changes are only written once the state has been left alone this long, so
+// that dragging a slider costs one write instead of dozens.
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+/// Set by the renderer whenever the state it shows changes.
+pub static CHANGED: Signal<CriticalSectionRawMutex, State> = Signal::new();
+
+/// The state last saved before the reset, if any.
+pub fn load(store: &mut Store<Partition>) -> Option<State> {
+    match store.load() {
+        Ok(Some((VERSION, payload))) => minicbor::decode(&payload).ok(),
+        Ok(Some((version, _))) => {
+            warn!("saved state version {} not understood", version);
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("state partition unreadable: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Writes the state to flash once it settles.
+#[task]
+pub async fn state_task(mut store: Store<Partition>) {
+    loop {
+        let mut state = watchdog::idle(Watched::State, CHANGED.wait()).await;
+        while let Either::First(newer) = select(CHANGED.wait(), Timer::after(SAVE_DELAY)).await {
+            watchdog::check_in(Watched::State);
+            state = newer;
+        }
+        let payload = minicbor::to_vec(&state).unwrap();
+        match store.save(VERSION, &payload) {
+            Ok(_) => info!("State saved"),
+            Err(e) => error!("failed to save state: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use embassy_net::Stack;
+use embassy_time::Instant;
+use minicbor::Encode;
+
+use crate::clock;
+use crate::config::SharedConfig;
+use crate::power::{self, Draw};
+use crate::version::{self, Build};
+use crate::watchdog::{self, Watched};
+
+/// Periodic status report published next to the command topic.
+#[derive(Encode)]
+#[cbor(map)]
+pub struct Telemetry {
+    #[n(0)]
+    pub uptime: u64,
+    #[n(1)]
+    pub ip: Option<[u8; 4]>,
+    #[n(2)]
+    pub prefix: u8,
+    #[n(3)]
+    pub gateway: Option<[u8; 4]>,
+    #[n(4)]
+    pub dhcp: bool,
+    // Unix time in seconds, once SNTP has set the clock.
+    #[n(5)]
+    pub time: Option<u64>,
+    #[n(6)]
+    pub build: Build,
+    // Reset reason code of the chip, and the task whose stall caused it.
+    #[n(7)]
+    pub reset: Option<u8>,
+    #[n(8)]
+    pub stalled: Option<Watched>,
+    // Estimated draw of the strip, while a current limit is configured.
+    #[n(9)]
+    pub draw: Option<Draw>,
+}
+
+impl Telemetry {
+    pub async fn collect(stack: Stack<'_>, config: &SharedConfig) -> Self {
+        let dhcp = config.lock().await.get().net.ipv4.is_none();
+        let v4 = stack.config_v4();
+        Telemetry {
+            uptime: Instant::now().as_secs(),
+            ip: v4.as_ref().map(|c| c.address.address().octets()),
+            prefix: v4.as_ref().map_or(0, |c| c.address.prefix_len()),
+            gateway: v4.and_then(|c| c.gateway).map(|g| g.octets()),
+            dhcp,
+            time: clock::utc(),
+            build: version::BUILD,
+            reset: esp_hal::system::reset_reason().map(|r| r as u8),
+            stalled: watchdog::stalled(),
+            draw: power::draw(),
+        }
+    }
+}
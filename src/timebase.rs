@@ -0,0 +1,202 @@
+use core::cell::Cell;
+
+use embassy_executor::task;
+use embassy_futures::select::{Either, select};
+use embassy_net::{
+    IpEndpoint, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::clock;
+use crate::config::SharedConfig;
+use crate::watchdog::{self, Watched};
+use crate::{error, info, warn};
+
+const PORT: u16 = 21330;
+const MAGIC: &[u8; 4] = b"WKTB";
+const BEACON: u8 = 1;
+const QUERY: u8 = 2;
+const REPLY: u8 = 3;
+
+const BEACON_PERIOD: Duration = Duration::from_secs(1);
+const QUERY_PERIOD: Duration = Duration::from_secs(2);
+// A leader not heard from for this long may be replaced by another one.
+const LEADER_TIMEOUT: Duration = Duration::from_secs(10);
+// Offset estimates kept to pick the one with the shortest round trip.
+const SAMPLES: usize = 8;
+
+// Microseconds to add to the local clock to get the shared one.
+static OFFSET: Mutex<CriticalSectionRawMutex, Cell<i64>> = Mutex::new(Cell::new(0));
+
+/// Shared time in microseconds; the same on every synchronized device.
+pub fn now() -> u64 {
+    let offset = OFFSET.lock(|o| o.get());
+    Instant::now().as_micros().wrapping_add_signed(offset)
+}
+
+pub fn set_offset(offset: i64) {
+    OFFSET.lock(|o| o.set(offset));
+}
+
+/// Keeps the shared time base: the leader broadcasts beacons and answers
+/// queries, followers estimate their offset to it from round trips. Without
+/// a leader to follow, the time base is the SNTP set wall clock, so devices
+/// that know the time agree on it anyway.
+#[task]
+pub async fn timebase_task(stack: Stack<'static>, config: &'static SharedConfig) {
+    let leader = config.lock().await.get().sync.leader;
+    watchdog::idle(Watched::Timebase, stack.wait_config_up()).await;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 256];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 256];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(PORT) {
+        error!("timebase bind error: {:?}", e);
+        watchdog::check_out(Watched::Timebase);
+        return;
+    }
+
+    if leader {
+        info!("Leading the shared time base");
+        lead(&mut socket).await
+    } else {
+        follow(&mut socket).await
+    }
+}
+
+async fn lead(socket: &mut UdpSocket<'_>) -> ! {
+    let broadcast = IpEndpoint::new(core::net::Ipv4Addr::BROADCAST.into(), PORT);
+    let mut ticker = Ticker::every(BEACON_PERIOD);
+    let mut buf = [0u8; 64];
+    loop {
+        watchdog::check_in(Watched::Timebase);
+        match select(socket.recv_from(&mut buf), ticker.next()).await {
+            Either::First(Ok((n, meta))) => {
+                let received = now();
+                let Some((QUERY, t0)) = parse(&buf[..n]) else {
+                    continue;
+                };
+                let mut reply = packet(REPLY, t0);
+                reply[13..21].copy_from_slice(&received.to_be_bytes());
+                reply[21..29].copy_from_slice(&now().to_be_bytes());
+                socket.send_to(&reply, meta.endpoint).await.ok();
+            }
+            Either::First(Err(_)) => {}
+            Either::Second(_) => {
+                // The leader hands UTC on, so that separate rooms, with or
+                // without a leader, stay in phase too.
+                follow_clock();
+                if let Err(e) = socket
+                    .send_to(&packet(BEACON, now())[..13], broadcast)
+                    .await
+                {
+                    warn!("timebase send error: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+async fn follow(socket: &mut UdpSocket<'_>) -> ! {
+    let mut leader: Option<(IpEndpoint, Instant)> = None;
+    let mut samples: heapless::Deque<(i64, u64), SAMPLES> = heapless::Deque::new();
+    let mut pending = None;
+    let mut ticker = Ticker::every(QUERY_PERIOD);
+    let mut buf = [0u8; 64];
+    loop {
+        watchdog::check_in(Watched::Timebase);
+        match select(socket.recv_from(&mut buf), ticker.next()).await {
+            Either::First(Ok((n, meta))) => {
+                let t3 = Instant::now().as_micros();
+                match parse(&buf[..n]) {
+                    Some((BEACON, _)) => {
+                        let stale = leader.is_none_or(|(endpoint, seen)| {
+                            endpoint != meta.endpoint && seen.elapsed() > LEADER_TIMEOUT
+                        });
+                        if stale {
+                            info!("Following time leader {}", meta.endpoint);
+                            samples.clear();
+                        }
+                        if stale || leader.is_some_and(|(e, _)| e == meta.endpoint) {
+                            leader = Some((meta.endpoint, Instant::now()));
+                        }
+                    }
+                    Some((REPLY, t0)) if pending == Some(t0) && n >= 29 => {
+                        pending = None;
+                        let t1 = u64::from_be_bytes(buf[13..21].try_into().unwrap());
+                        let t2 = u64::from_be_bytes(buf[21..29].try_into().unwrap());
+                        let (offset, delay) = estimate(t0, t1, t2, t3);
+                        if samples.is_full() {
+                            samples.pop_front();
+                        }
+                        samples.push_back((offset, delay)).ok();
+                        // The shortest round trip is the least skewed by queuing.
+                        if let Some((offset, _)) = samples.iter().min_by_key(|(_, d)| *d) {
+                            set_offset(*offset);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Either::First(Err(_)) => {}
+            Either::Second(_) => {
+                let Some((endpoint, _)) =
+                    leader.filter(|(_, seen)| seen.elapsed() <= LEADER_TIMEOUT)
+                else {
+                    follow_clock();
+                    continue;
+                };
+                let t0 = Instant::now().as_micros();
+                pending = Some(t0);
+                socket
+                    .send_to(&packet(QUERY, t0)[..13], endpoint)
+                    .await
+                    .ok();
+            }
+        }
+    }
+}
+
+/// Takes the offset to the wall clock, once SNTP has set it.
+fn follow_clock() {
+    if let Some(offset) = clock::offset_micros() {
+        set_offset(offset);
+    }
+}
+
+/// Offset of the leader's clock and the network round trip, NTP style: `t0`
+/// and `t3` are local send and receive times, `t1` and `t2` the leader's.
+fn estimate(t0: u64, t1: u64, t2: u64, t3: u64) -> (i64, u64) {
+    let (t0, t1, t2, t3) = (t0 as i64, t1 as i64, t2 as i64, t3 as i64);
+    let offset = ((t1 - t0) + (t2 - t3)) / 2;
+    let delay = (t3 - t0) - (t2 - t1);
+    (offset, delay.max(0) as u64)
+}
+
+fn packet(kind: u8, time: u64) -> [u8; 29] {
+    let mut p = [0u8; 29];
+    p[..4].copy_from_slice(MAGIC);
+    p[4] = kind;
+    p[5..13].copy_from_slice(&time.to_be_bytes());
+    p
+}
+
+fn parse(p: &[u8]) -> Option<(u8, u64)> {
+    if p.get(..4)? != MAGIC {
+        return None;
+    }
+    Some((
+        *p.get(4)?,
+        u64::from_be_bytes(p.get(5..13)?.try_into().ok()?),
+    ))
+}
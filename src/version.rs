@@ -0,0 +1,27 @@
+use minicbor::Encode;
+use serde::Serialize;
+
+/// Crate version and git hash; what the app descriptor reports as version.
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("WINK_GIT_HASH"));
+pub const BUILD_TIME: &str = env!("WINK_BUILD_TIME");
+// Like "Oct 18 2026".
+pub const BUILD_DATE: &str = env!("WINK_BUILD_DATE");
+
+/// Which build a node runs, for the birth message, telemetry and the API.
+#[derive(Clone, Copy, Encode, Serialize)]
+#[cbor(map)]
+pub struct Build {
+    #[n(0)]
+    pub version: &'static str,
+    #[n(1)]
+    pub git: &'static str,
+    // UTC.
+    #[n(2)]
+    pub built: &'static str,
+}
+
+pub const BUILD: Build = Build {
+    version: env!("CARGO_PKG_VERSION"),
+    git: env!("WINK_GIT_HASH"),
+    built: concat!(env!("WINK_BUILD_DATE"), " ", env!("WINK_BUILD_TIME")),
+};
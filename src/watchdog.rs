@@ -0,0 +1,192 @@
+use core::cell::Cell;
+use core::pin::pin;
+use core::ptr::addr_of_mut;
+
+use defmt::Debug2Format;
+use embassy_executor::task;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Ticker, WithTimeout};
+use esp_hal::peripherals::TIMG0;
+use esp_hal::rtc_cntl::{Rwdt, RwdtStage};
+use esp_hal::timer::timg::{MwdtStage, Wdt};
+use minicbor::Encode;
+
+use crate::{error, info, warn};
+
+/// How often an idle task should still check in.
+pub const CHECK_IN: Duration = Duration::from_secs(2);
+const FEED_PERIOD: Duration = Duration::from_secs(1);
+// The hardware watchdogs only fire when the supervisor itself stops running,
+// as when a task spins without yielding. The RTC one also resets the
+// peripherals the main one leaves alone.
+const MWDT_TIMEOUT: u64 = 5;
+const RWDT_TIMEOUT: u64 = 15;
+
+// Marks a stall record as written before the reset rather than left over
+// from power on.
+const MAGIC: u32 = 0x5741_5443;
+
+/// Long running tasks the supervisor expects to hear from.
+#[derive(Clone, Copy, PartialEq, Encode, defmt::Format)]
+#[cbor(index_only)]
+pub enum Watched {
+    #[n(0)]
+    Render,
+    #[n(1)]
+    Mqtt,
+    #[n(2)]
+    Connection,
+    #[n(3)]
+    Api,
+    // Any WebSocket client being served by the API tasks.
+    #[n(4)]
+    Ws,
+    #[n(5)]
+    Dmx,
+    #[n(6)]
+    Realtime,
+    #[n(7)]
+    Timebase,
+    #[n(8)]
+    Schedule,
+    #[n(9)]
+    State,
+    #[n(10)]
+    Ota,
+}
+
+impl Watched {
+    const ALL: [Watched; 11] = [
+        Watched::Render,
+        Watched::Mqtt,
+        Watched::Connection,
+        Watched::Api,
+        Watched::Ws,
+        Watched::Dmx,
+        Watched::Realtime,
+        Watched::Timebase,
+        Watched::Schedule,
+        Watched::State,
+        Watched::Ota,
+    ];
+
+    /// How long the task may go without checking in.
+    fn deadline(self) -> Duration {
+        match self {
+            Watched::Render | Watched::Dmx | Watched::Realtime | Watched::State => {
+                Duration::from_secs(10)
+            }
+            Watched::Timebase | Watched::Schedule => Duration::from_secs(30),
+            // Scans and connection attempts take seconds each.
+            Watched::Connection => Duration::from_secs(60),
+            // Bounded by the socket timeouts of a request or a download.
+            Watched::Api | Watched::Ws | Watched::Ota => Duration::from_secs(60),
+            // Waits on the broker at least once per telemetry period.
+            Watched::Mqtt => Duration::from_secs(180),
+        }
+    }
+}
+
+// Deadlines count from boot until a task first checks in. Tasks that have
+// checked out have none.
+static CHECK_INS: Mutex<CriticalSectionRawMutex, Cell<[Option<Instant>; Watched::ALL.len()]>> =
+    Mutex::new(Cell::new(
+        [Some(Instant::from_ticks(0)); Watched::ALL.len()],
+    ));
+static STALLED: Mutex<CriticalSectionRawMutex, Cell<Option<Watched>>> = Mutex::new(Cell::new(None));
+
+// Survives the reset the supervisor triggers, but not a power cycle.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut RECORD: [u32; 2] = [0; 2];
+
+/// Tells the supervisor that `task` is still making progress.
+pub fn check_in(task: Watched) {
+    set(task, Some(Instant::now()));
+}
+
+/// Tells the supervisor not to expect `task` until it checks in again, as
+/// when it has ended or has nothing to serve.
+pub fn check_out(task: Watched) {
+    set(task, None);
+}
+
+fn set(task: Watched, time: Option<Instant>) {
+    CHECK_INS.lock(|c| {
+        let mut times = c.get();
+        times[task as usize] = time;
+        c.set(times);
+    });
+}
+
+/// Awaits `fut`, checking in for `task` every `CHECK_IN` meanwhile. For
+/// waits without a bound, as for the next request or packet.
+pub async fn idle<F: Future>(task: Watched, fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    loop {
+        check_in(task);
+        if let Ok(output) = fut.as_mut().with_timeout(CHECK_IN).await {
+            return output;
+        }
+    }
+}
+
+/// The task that stalled and caused the last reset, if one did.
+pub fn stalled() -> Option<Watched> {
+    STALLED.lock(Cell::get)
+}
+
+/// Reports why the device was reset, and takes over the stall record left
+/// by the supervisor.
+pub fn init() {
+    let record = unsafe { addr_of_mut!(RECORD).read_volatile() };
+    unsafe { addr_of_mut!(RECORD).write_volatile([0; 2]) };
+    let stalled = match record {
+        [MAGIC, code] => Watched::ALL.into_iter().find(|t| *t as u32 == code),
+        _ => None,
+    };
+    info!(
+        "Reset reason: {:?}",
+        Debug2Format(&esp_hal::system::reset_reason())
+    );
+    if let Some(task) = stalled {
+        warn!("Reset after {} stalled", task);
+    }
+    STALLED.lock(|s| s.set(stalled));
+    // Only expected while a WebSocket client is connected.
+    check_out(Watched::Ws);
+}
+
+fn overdue(now: Instant) -> Option<Watched> {
+    let times = CHECK_INS.lock(Cell::get);
+    Watched::ALL.into_iter().find(|t| {
+        times[*t as usize].is_some_and(|at| now.saturating_duration_since(at) > t.deadline())
+    })
+}
+
+/// Keeps the hardware watchdogs fed for as long as every watched task checks
+/// in on time, and resets the device as soon as one does not.
+#[task]
+pub async fn supervisor_task(mut mwdt: Wdt<TIMG0<'static>>, mut rwdt: Rwdt) {
+    mwdt.set_timeout(
+        MwdtStage::Stage0,
+        esp_hal::time::Duration::from_secs(MWDT_TIMEOUT),
+    );
+    mwdt.enable();
+    rwdt.set_timeout(
+        RwdtStage::Stage0,
+        esp_hal::time::Duration::from_secs(RWDT_TIMEOUT),
+    );
+    rwdt.enable();
+
+    let mut ticker = Ticker::every(FEED_PERIOD);
+    loop {
+        ticker.next().await;
+        if let Some(task) = overdue(Instant::now()) {
+            error!("{} missed its deadline, resetting", task);
+            unsafe { addr_of_mut!(RECORD).write_volatile([MAGIC, task as u32]) };
+            esp_hal::system::software_reset();
+        }
+        mwdt.feed();
+        rwdt.feed();
+    }
+}
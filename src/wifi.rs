@@ -0,0 +1,224 @@
+use core::cell::Cell;
+
+use alloc::{format, string::String, vec, vec::Vec};
+use embassy_executor::task;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_radio::wifi::event::{self, EventExt};
+use esp_radio::wifi::{
+    AccessPointConfig, AccessPointInfo, ClientConfig, ModeConfig, ScanConfig, WifiController,
+    WifiEvent, WifiStaState,
+};
+
+use crate::config::{NetConfig, Network, SharedConfig};
+use crate::portal;
+use crate::watchdog::{self, Watched};
+use crate::{error, info, warn};
+
+// How long the portal has to go unused before the known networks are looked
+// for again, as when the router came up after the device gave up on it.
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(300);
+// Known networks out of range for this long lead to the portal too, for a
+// device moved to another site. Long enough to outlast a router that boots
+// slower than the device after a power cut.
+const ABSENT_LIMIT: Duration = Duration::from_secs(15 * 60);
+
+// The reason the driver gave for the last failed connection attempt.
+static DISCONNECT_REASON: Mutex<CriticalSectionRawMutex, Cell<u8>> = Mutex::new(Cell::new(0));
+
+#[task]
+pub async fn connection(mut controller: WifiController<'static>, config: &'static SharedConfig) {
+    info!("start connection task");
+    info!("Device capabilities: {:?}", controller.capabilities());
+    event::StaDisconnected::update_handler(|event| {
+        DISCONNECT_REASON.lock(|r| r.set(event.reason()));
+    });
+    let wifi = config.lock().await.get().wifi.clone();
+    loop {
+        join(&mut controller, &wifi.networks, wifi.max_failures).await;
+
+        start_access_point(&mut controller).await;
+        portal::START.signal(());
+        // The portal reboots into station mode once it has saved a
+        // configuration.
+        wait_for_network(&mut controller, &wifi.networks).await;
+        info!("Known network in range again, leaving the portal");
+        controller.stop_async().await.ok();
+    }
+}
+
+/// Keeps the station connected to the best known network, and returns once
+/// every network has turned the device away too often, or none has been in
+/// range for a long time.
+async fn join(controller: &mut WifiController<'static>, networks: &[Network], max_failures: u8) {
+    // Failed attempts since the last connection, after which the next network
+    // gets a turn.
+    let mut failures = vec![0u8; networks.len()];
+    // Those of them where the network turned the device away, as with a wrong
+    // password. Only these give up on a network for good.
+    let mut rejections = vec![0u8; networks.len()];
+    let mut last_seen = Instant::now();
+    loop {
+        watchdog::check_in(Watched::Connection);
+        if esp_radio::wifi::sta_state() == WifiStaState::Connected {
+            // wait until we're no longer connected
+            let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+            watchdog::idle(Watched::Connection, disconnected).await;
+            Timer::after(Duration::from_millis(5000)).await
+        }
+        if rejections.iter().all(|r| *r >= max_failures) {
+            warn!("No usable Wi-Fi network");
+            return;
+        }
+        if !matches!(controller.is_started(), Ok(true)) {
+            controller
+                .set_config(&ModeConfig::Client(ClientConfig::default()))
+                .unwrap();
+            info!("Starting wifi");
+            controller.start_async().await.unwrap();
+            info!("Wifi started!");
+        }
+
+        let result = scan(controller).await;
+        let Some((index, ap)) = select(networks, &result, &failures, max_failures) else {
+            if select(networks, &result, &rejections, max_failures).is_some() {
+                // Every network in range has had its turn; start another
+                // round with those that did not turn the device away.
+                failures.clone_from(&rejections);
+                continue;
+            }
+            warn!("No known network in range");
+            if last_seen.elapsed() >= ABSENT_LIMIT {
+                return;
+            }
+            Timer::after(Duration::from_millis(5000)).await;
+            continue;
+        };
+        last_seen = Instant::now();
+        let network = &networks[index];
+        let station_config = ModeConfig::Client(
+            ClientConfig::default()
+                .with_ssid(network.ssid.clone())
+                .with_password(network.password.clone())
+                .with_bssid(ap.bssid)
+                .with_channel(ap.channel),
+        );
+        if let Err(e) = controller.set_config(&station_config) {
+            error!("Wi-Fi config refused: {:?}", e);
+            failures[index] += 1;
+            rejections[index] += 1;
+            continue;
+        }
+        info!(
+            "About to connect to {} ({} dBm)",
+            ap.ssid.as_str(),
+            ap.signal_strength
+        );
+
+        DISCONNECT_REASON.lock(|r| r.set(0));
+        match controller.connect_async().await {
+            Ok(_) => {
+                info!("Wifi connected!");
+                failures.iter_mut().for_each(|f| *f = 0);
+                rejections.iter_mut().for_each(|r| *r = 0);
+            }
+            Err(e) => {
+                let reason = DISCONNECT_REASON.lock(Cell::get);
+                error!("Failed to connect to wifi: {:?}, reason {}", e, reason);
+                failures[index] += 1;
+                // A network that is only out of reach for now, or busy, may
+                // well take the device later on.
+                if rejected(reason) {
+                    rejections[index] += 1;
+                }
+                Timer::after(Duration::from_millis(5000)).await
+            }
+        }
+    }
+}
+
+/// Whether a disconnect reason means that the network turned the device
+/// away, as with a wrong password.
+fn rejected(reason: u8) -> bool {
+    matches!(
+        reason,
+        // AUTH_EXPIRE, ASSOC_EXPIRE, NOT_AUTHED, NOT_ASSOCED, ASSOC_NOT_AUTHED
+        2 | 4 | 6 | 7 | 9
+        // 4WAY_HANDSHAKE_TIMEOUT, 802_1X_AUTH_FAILED
+        | 15 | 23
+        // AUTH_FAIL, ASSOC_FAIL, HANDSHAKE_TIMEOUT
+        | 202..=204
+    )
+}
+
+/// Waits for a known network to be in range while the portal goes unused.
+async fn wait_for_network(controller: &mut WifiController<'static>, networks: &[Network]) {
+    loop {
+        watchdog::idle(Watched::Connection, Timer::after(PORTAL_TIMEOUT)).await;
+        if portal::used_within(PORTAL_TIMEOUT) {
+            continue;
+        }
+        let result = scan(controller).await;
+        if result
+            .iter()
+            .any(|ap| networks.iter().any(|n| n.ssid == ap.ssid))
+        {
+            return;
+        }
+    }
+}
+
+async fn scan(controller: &mut WifiController<'static>) -> Vec<AccessPointInfo> {
+    info!("Scan");
+    let scan_config = ScanConfig::default().with_max(10);
+    let result = controller
+        .scan_with_config_async(scan_config)
+        .await
+        .unwrap_or_default();
+    for ap in &result {
+        info!("{:?}", ap);
+    }
+    result
+}
+
+/// Picks the strongest access point of a known network that has not yet used
+/// up its attempts, returning the network's index.
+fn select<'a>(
+    networks: &[Network],
+    scan: &'a [AccessPointInfo],
+    failures: &[u8],
+    max_failures: u8,
+) -> Option<(usize, &'a AccessPointInfo)> {
+    scan.iter()
+        .filter_map(|ap| {
+            let index = networks.iter().position(|n| n.ssid == ap.ssid)?;
+            (failures[index] < max_failures).then_some((index, ap))
+        })
+        // On equal signal prefer the network listed first.
+        .max_by_key(|(index, ap)| (ap.signal_strength, core::cmp::Reverse(*index)))
+}
+
+async fn start_access_point(controller: &mut WifiController<'static>) {
+    if matches!(controller.is_started(), Ok(true)) {
+        controller.stop_async().await.ok();
+    }
+    let mac = esp_radio::wifi::ap_mac();
+    let ssid = format!("wink-{:02x}{:02x}", mac[4], mac[5]);
+    info!("Starting access point {}", ssid.as_str());
+    // The station side only scans for the known networks meanwhile.
+    let ap_config = ModeConfig::ApSta(
+        ClientConfig::default(),
+        AccessPointConfig::default().with_ssid(ssid),
+    );
+    controller.set_config(&ap_config).unwrap();
+    controller.start_async().await.unwrap();
+}
+
+/// The configured hostname, or one derived from the MAC address.
+pub fn device_name(net: &NetConfig) -> String {
+    if !net.hostname.is_empty() {
+        return net.hostname.clone();
+    }
+    let mac = esp_radio::wifi::sta_mac();
+    format!("wink-{:02x}{:02x}", mac[4], mac[5])
+}
@@ -0,0 +1,212 @@
+use alloc::vec::Vec;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use embassy_futures::select::{Either, select};
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Sender};
+use embassy_time::{Duration, Ticker};
+use embedded_io_async::{Read, Write};
+use minicbor::Decode;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::config::EffectConfig;
+use crate::http::{self, Request};
+use crate::led::{self, Command, Light, PresetCall};
+use crate::watchdog::{self, Watched};
+use crate::{info, warn};
+
+// Fixed by RFC 6455 to derive `Sec-WebSocket-Accept` from the client's key.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const FRAME_PERIOD: Duration = Duration::from_millis(100);
+const MAX_MESSAGE: usize = 256;
+
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// A message from the browser: JSON in text frames, CBOR in binary ones.
+#[derive(Decode, Deserialize)]
+#[cbor(map)]
+struct Message {
+    #[n(0)]
+    light: Option<Light>,
+    #[n(1)]
+    effect: Option<EffectConfig>,
+    #[n(2)]
+    preset: Option<PresetCall>,
+}
+
+pub fn is_upgrade(request: &Request) -> bool {
+    request
+        .header("upgrade")
+        .is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+}
+
+/// Completes the handshake, then streams the frame buffer whenever it changes
+/// (at most every `FRAME_PERIOD`) and forwards the commands received.
+pub async fn serve(
+    socket: &mut TcpSocket<'_>,
+    request: &Request,
+    commands: &Sender<'static, NoopRawMutex, Command, 3>,
+) {
+    let Some(key) = request.header("sec-websocket-key") else {
+        http::respond(socket, "400 Bad Request", "text/plain", b"")
+            .await
+            .ok();
+        return;
+    };
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(GUID.as_bytes());
+    let mut accept = [0u8; 28];
+    STANDARD.encode_slice(sha.finalize(), &mut accept).unwrap();
+    let head = alloc::format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {}\r\n\r\n",
+        core::str::from_utf8(&accept).unwrap()
+    );
+    if socket.write_all(head.as_bytes()).await.is_err() {
+        return;
+    }
+    info!("WebSocket client connected");
+
+    // Keeps an idle preview from hitting the socket timeout.
+    socket.set_keep_alive(Some(Duration::from_secs(5)));
+    socket.set_timeout(Some(Duration::from_secs(30)));
+
+    let mut ticker = Ticker::every(FRAME_PERIOD);
+    let mut sent = None;
+    let mut payload = [0u8; MAX_MESSAGE];
+    loop {
+        // Serving a client is progress of the API task too, which may have
+        // no other instance left to check in.
+        watchdog::check_in(Watched::Ws);
+        watchdog::check_in(Watched::Api);
+        match select(socket.wait_read_ready(), ticker.next()).await {
+            Either::First(_) => {
+                let Some((opcode, len)) = read_frame(socket, &mut payload).await else {
+                    break;
+                };
+                let message = &payload[..len];
+                match opcode {
+                    OP_TEXT => match serde_json_core::from_slice::<Message>(message) {
+                        Ok((m, _)) => dispatch(m, commands).await,
+                        Err(_) => warn!("ws: bad JSON command"),
+                    },
+                    OP_BINARY => match minicbor::decode::<Message>(message) {
+                        Ok(m) => dispatch(m, commands).await,
+                        Err(_) => warn!("ws: bad CBOR command"),
+                    },
+                    OP_PING => {
+                        if write_frame(socket, OP_PONG, message).await.is_err() {
+                            break;
+                        }
+                    }
+                    OP_CLOSE => {
+                        write_frame(socket, OP_CLOSE, message).await.ok();
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            Either::Second(_) => {
+                let frame = led::frame();
+                if sent.as_ref() == Some(&frame) {
+                    continue;
+                }
+                let bytes: Vec<u8> = frame.iter().flat_map(|c| [c.r, c.g, c.b]).collect();
+                if write_frame(socket, OP_BINARY, &bytes).await.is_err() {
+                    break;
+                }
+                sent = Some(frame);
+            }
+        }
+    }
+    watchdog::check_out(Watched::Ws);
+    info!("WebSocket client gone");
+}
+
+async fn dispatch(message: Message, commands: &Sender<'static, NoopRawMutex, Command, 3>) {
+    if let Some(light) = message.light {
+        commands.send(Command::Light(light)).await;
+    }
+    if let Some(effect) = message.effect {
+        commands.send(Command::Effect(effect)).await;
+    }
+    if let Some(call) = message.preset {
+        commands.send(call.into()).await;
+    }
+}
+
+/// Reads one unfragmented, masked client frame into `buf`.
+async fn read_frame(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8; MAX_MESSAGE],
+) -> Option<(u8, usize)> {
+    let mut head = [0u8; 2];
+    socket.read_exact(&mut head).await.ok()?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut ext = [0u8; 2];
+            socket.read_exact(&mut ext).await.ok()?;
+            u16::from_be_bytes(ext) as usize
+        }
+        127 => return None,
+        l => l as usize,
+    };
+    if !fin || !masked || len > MAX_MESSAGE {
+        warn!("ws: unsupported frame");
+        return None;
+    }
+
+    let mut mask = [0u8; 4];
+    socket.read_exact(&mut mask).await.ok()?;
+    socket.read_exact(&mut buf[..len]).await.ok()?;
+    for (i, b) in buf[..len].iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Some((opcode, len))
+}
+
+async fn write_frame(
+    socket: &mut TcpSocket<'_>,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<(), embassy_net::tcp::Error> {
+    let mut head = Vec::with_capacity(4);
+    head.push(0x80 | opcode);
+    match payload.len() {
+        l @ 0..=125 => head.push(l as u8),
+        l => {
+            head.push(126);
+            head.extend_from_slice(&(l as u16).to_be_bytes());
+        }
+    }
+    socket.write_all(&head).await?;
+    socket.write_all(payload).await?;
+    socket.flush().await
+}
+
+/// Live view of the strip with an on switch and a color picker.
+pub const PAGE: &str = r#"<!DOCTYPE html><html><head><meta name="viewport" content="width=device-width">
+<title>wink</title><style>#s{display:flex;flex-wrap:wrap}
+#s i{width:16px;height:16px;margin:1px;border-radius:50%;background:#000}</style></head>
+<body><div id="s"></div><p><input type="color" id="c">
+<label><input type="checkbox" id="o"> on</label></p><script>
+const s=document.getElementById('s'),ws=new WebSocket('ws://'+location.host+'/ws');
+ws.binaryType='arraybuffer';
+ws.onmessage=e=>{const d=new Uint8Array(e.data),n=d.length/3;
+while(s.children.length<n)s.appendChild(document.createElement('i'));
+for(let i=0;i<n;i++)s.children[i].style.background=`rgb(${d[3*i]},${d[3*i+1]},${d[3*i+2]})`;};
+document.getElementById('o').onchange=e=>ws.send(JSON.stringify({light:{on:e.target.checked,num:0}}));
+document.getElementById('c').oninput=e=>{const v=parseInt(e.target.value.slice(1),16),
+r=v>>16,g=v>>8&255,b=v&255,mx=Math.max(r,g,b),d=mx-Math.min(r,g,b);let h=0;
+if(d)h=((mx==r?(g-b)/d:mx==g?2+(b-r)/d:4+(r-g)/d)+6)%6/6;
+ws.send(JSON.stringify({effect:{hue:Math.round(h*255),sat:mx?Math.round(d/mx*255):0}}));};
+</script></body></html>"#;
@@ -0,0 +1,104 @@
+use crate::config::{DstRule, TimeConfig};
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DateTime {
+    pub year: i32,
+    // 1-12
+    pub month: u8,
+    // 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    // 0 is Sunday.
+    pub weekday: u8,
+}
+
+impl DateTime {
+    pub fn from_unix(t: i64) -> Self {
+        let days = t.div_euclid(86_400);
+        let secs = t.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+            weekday: weekday(days),
+        }
+    }
+}
+
+/// Shifts a Unix time into the configured zone, daylight saving included.
+pub fn to_local(zone: &TimeConfig, t: i64) -> i64 {
+    let standard = t + zone.offset_min as i64 * 60;
+    match &zone.dst {
+        Some(rule) if in_dst(rule, standard) => standard + rule.save_min as i64 * 60,
+        _ => standard,
+    }
+}
+
+/// Whether standard local time `t` falls in daylight saving time.
+fn in_dst(rule: &DstRule, t: i64) -> bool {
+    let year = DateTime::from_unix(t).year;
+    let switch = |month: u8, week: u8, hour: u8| {
+        nth_weekday(year, month, week, rule.weekday) * 86_400 + hour as i64 * 3600
+    };
+    let start = switch(rule.start_month, rule.start_week, rule.start_hour);
+    let end = switch(rule.end_month, rule.end_week, rule.end_hour);
+    if start < end {
+        start <= t && t < end
+    } else {
+        // Southern hemisphere: the saving period spans the new year.
+        t < end || start <= t
+    }
+}
+
+/// Days since the epoch of the `week`th `weekday` of a month, 5 meaning the
+/// last one.
+fn nth_weekday(year: i32, month: u8, week: u8, weekday: u8) -> i64 {
+    let first = days_from_civil(year, month, 1);
+    let mut day = first + (weekday as i64 - self::weekday(first) as i64).rem_euclid(7);
+    day += 7 * (week.clamp(1, 5) as i64 - 1);
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    while day >= days_from_civil(next_year, next_month, 1) {
+        day -= 7;
+    }
+    day
+}
+
+fn weekday(days: i64) -> u8 {
+    // 1970-01-01 was a Thursday.
+    (days + 4).rem_euclid(7) as u8
+}
+
+// Proleptic Gregorian calendar conversions after Howard Hinnant's
+// `days_from_civil` and `civil_from_days`.
+pub fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year } as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+pub fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400 + (month <= 2) as i64) as i32;
+    (year, month, day)
+}
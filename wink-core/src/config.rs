@@ -0,0 +1,999 @@
+use alloc::{string::String, vec, vec::Vec};
+use embedded_storage::nor_flash::NorFlash;
+use minicbor::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::log::Level;
+
+/// Bump this whenever existing fields of `Config` change shape and add an arm
+/// to `migrate`. Fields that are only added get `#[cbor(default)]` instead.
+pub const SCHEMA_VERSION: u16 = 2;
+
+/// Pixels the firmware keeps buffers for, and the default strip length.
+pub const LEDS: usize = 50;
+/// Longest SSID and passphrase the radio takes, in bytes.
+pub const MAX_SSID: usize = 32;
+pub const MAX_PASSWORD: usize = 64;
+/// Known networks kept, which bounds the config record.
+pub const MAX_NETWORKS: usize = 8;
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct Config {
+    #[n(0)]
+    pub wifi: WifiConfig,
+    #[n(1)]
+    pub mqtt: MqttConfig,
+    #[n(2)]
+    pub strip: StripConfig,
+    #[n(3)]
+    pub effect: EffectConfig,
+    #[n(4)]
+    #[cbor(default)]
+    pub net: NetConfig,
+    #[n(5)]
+    #[cbor(default)]
+    pub realtime: RealtimeConfig,
+    #[n(6)]
+    #[cbor(default)]
+    pub sync: SyncConfig,
+    #[n(7)]
+    #[cbor(default)]
+    pub time: TimeConfig,
+    #[n(8)]
+    #[cbor(default)]
+    pub schedules: Vec<Schedule>,
+    #[n(9)]
+    #[cbor(default)]
+    pub presets: Vec<Preset>,
+    #[n(10)]
+    #[cbor(default)]
+    pub power_on: PowerOn,
+    // Version of the last update installed; older images are refused.
+    #[n(11)]
+    #[cbor(default)]
+    pub ota_version: u32,
+    #[n(12)]
+    #[cbor(default)]
+    pub log: LogConfig,
+    #[n(13)]
+    #[cbor(default)]
+    pub power: PowerConfig,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct WifiConfig {
+    // In order of preference when several are equally strong.
+    #[n(0)]
+    pub networks: Vec<Network>,
+    // Failed attempts on a network before moving on to the next one.
+    #[n(1)]
+    pub max_failures: u8,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct Network {
+    #[n(0)]
+    pub ssid: String,
+    #[n(1)]
+    pub password: String,
+}
+
+#[derive(Clone, Default, Encode, Decode)]
+#[cbor(map)]
+pub struct NetConfig {
+    // Sent with DHCP requests when not empty.
+    #[n(0)]
+    pub hostname: String,
+    // DHCP is used when no static address is set.
+    #[n(1)]
+    pub ipv4: Option<StaticIpv4>,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct StaticIpv4 {
+    #[n(0)]
+    pub address: [u8; 4],
+    #[n(1)]
+    pub prefix: u8,
+    #[n(2)]
+    pub gateway: Option<[u8; 4]>,
+    #[n(3)]
+    pub dns: Vec<[u8; 4]>,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct MqttConfig {
+    // Either a dotted IPv4 address or a name resolved through DNS; when empty
+    // the broker is only looked up through mDNS.
+    #[n(0)]
+    pub host: String,
+    #[n(1)]
+    pub port: u16,
+    #[n(2)]
+    pub client_id: String,
+    #[n(3)]
+    pub topic: String,
+    // Browse for `_mqtt._tcp` first and only use `host` when nothing answers.
+    #[n(4)]
+    #[cbor(default)]
+    pub discover: bool,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct StripConfig {
+    #[n(0)]
+    pub leds: u16,
+    #[n(1)]
+    pub brightness: u8,
+}
+
+#[derive(Clone, PartialEq, Encode, Decode, Serialize, Deserialize)]
+#[cbor(map)]
+pub struct EffectConfig {
+    #[n(0)]
+    pub hue: u8,
+    #[n(1)]
+    pub sat: u8,
+    // Hue steps per second, following the shared time base so that strips
+    // running the same effect stay in phase. Zero keeps the color still.
+    #[n(2)]
+    #[cbor(default)]
+    #[serde(default)]
+    pub speed: u8,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct RealtimeConfig {
+    // First universe mapped onto the strip, numbered the same for E1.31 and
+    // Art-Net; further pixels continue on the next universes.
+    #[n(0)]
+    pub universe: u16,
+    // DMX address (1-512) of the first pixel's red channel in `universe`.
+    #[n(1)]
+    pub channel: u16,
+    // Back to the regular effect once no realtime data came in for this long,
+    // unless the protocol carries its own timeout.
+    #[n(2)]
+    pub timeout_ms: u32,
+}
+
+impl Default for RealtimeConfig {
+    fn default() -> Self {
+        RealtimeConfig {
+            universe: 1,
+            channel: 1,
+            timeout_ms: 2500,
+        }
+    }
+}
+
+#[derive(Clone, Default, Encode, Decode)]
+#[cbor(map)]
+pub struct SyncConfig {
+    // At most one device per room should lead; the others follow its beacon.
+    // Without a leader, devices share the SNTP time instead.
+    #[n(0)]
+    pub leader: bool,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct TimeConfig {
+    // SNTP servers, tried in order.
+    #[n(0)]
+    pub servers: Vec<String>,
+    // Standard time offset from UTC.
+    #[n(1)]
+    pub offset_min: i16,
+    #[n(2)]
+    pub dst: Option<DstRule>,
+    // Needed for schedules relative to sunrise or sunset.
+    #[n(3)]
+    #[cbor(default)]
+    pub location: Option<Location>,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        TimeConfig {
+            servers: vec!["pool.ntp.org".into()],
+            offset_min: 0,
+            dst: None,
+            location: None,
+        }
+    }
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct Location {
+    // Degrees north.
+    #[n(0)]
+    pub latitude: f32,
+    // Degrees east.
+    #[n(1)]
+    pub longitude: f32,
+}
+
+/// When daylight saving time starts and ends, e.g. for the EU: last Sunday
+/// (week 5) of March to last Sunday of October, both at 02:00 standard time.
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct DstRule {
+    #[n(0)]
+    pub start_month: u8,
+    // 1-4, or 5 for the last one in the month.
+    #[n(1)]
+    pub start_week: u8,
+    // Hour of the switch, in standard time.
+    #[n(2)]
+    pub start_hour: u8,
+    #[n(3)]
+    pub end_month: u8,
+    #[n(4)]
+    pub end_week: u8,
+    #[n(5)]
+    pub end_hour: u8,
+    // 0 is Sunday.
+    #[n(6)]
+    pub weekday: u8,
+    #[n(7)]
+    pub save_min: u8,
+}
+
+/// Something to do on given days at a local time of day, e.g. fade to warm
+/// white over 15 minutes at 07:00 on weekdays, or switch on 30 minutes
+/// before sunset.
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct Schedule {
+    // Bit 0 is Sunday; for sun anchors, the day of the sunrise or sunset.
+    #[n(0)]
+    pub days: u8,
+    // Minutes after the anchor, negative for before.
+    #[n(1)]
+    pub minute: i16,
+    #[n(2)]
+    pub action: Action,
+    #[n(3)]
+    pub fade_s: u32,
+    #[n(4)]
+    #[cbor(default)]
+    pub anchor: Anchor,
+}
+
+#[derive(Clone, Copy, Default, Encode, Decode, defmt::Format)]
+#[cbor(index_only)]
+pub enum Anchor {
+    #[default]
+    #[n(0)]
+    Midnight,
+    #[n(1)]
+    Sunrise,
+    #[n(2)]
+    Sunset,
+}
+
+#[derive(Clone, Encode, Decode)]
+pub enum Action {
+    #[n(0)]
+    On,
+    #[n(1)]
+    Off,
+    // Switches on with this effect.
+    #[n(2)]
+    Effect(#[n(0)] EffectConfig),
+    #[n(3)]
+    Preset(#[n(0)] u8),
+}
+
+/// What the strip shows after a reset.
+#[derive(Clone, Copy, Default, Encode, Decode)]
+pub enum PowerOn {
+    #[n(0)]
+    Off,
+    // The state saved before the reset.
+    #[default]
+    #[n(1)]
+    Last,
+    #[n(2)]
+    Preset(#[n(0)] u8),
+}
+
+/// A named snapshot of what the strip shows.
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct Preset {
+    #[n(0)]
+    pub id: u8,
+    #[n(1)]
+    pub name: String,
+    #[n(2)]
+    pub on: bool,
+    #[n(3)]
+    pub brightness: u8,
+    #[n(4)]
+    pub effect: EffectConfig,
+}
+
+#[derive(Clone, Default, Encode, Decode)]
+#[cbor(map)]
+pub struct PowerConfig {
+    #[n(0)]
+    pub wifi: WifiPowerSave,
+    // A relay or MOSFET switching the strip's supply, if there is one.
+    #[n(1)]
+    pub relay: Option<RelayConfig>,
+    // Keeps the strip's estimated draw within what the supply delivers.
+    #[n(2)]
+    #[cbor(default)]
+    pub limit: Option<CurrentLimit>,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct CurrentLimit {
+    // What the supply may deliver to the strip.
+    #[n(0)]
+    pub budget_ma: u32,
+    // Draw of one color channel at full level, about 20 mA on a WS2812B.
+    #[n(1)]
+    pub channel_ma: u16,
+    // Draw of a pixel showing black, about 1 mA.
+    #[n(2)]
+    pub idle_ma: u16,
+}
+
+/// How much the radio sleeps between beacons; more sleep means more latency
+/// before commands are handled.
+#[derive(Clone, Copy, Default, Encode, Decode)]
+#[cbor(index_only)]
+pub enum WifiPowerSave {
+    #[n(0)]
+    None,
+    // Wakes for every DTIM beacon.
+    #[n(1)]
+    Minimum,
+    #[default]
+    #[n(2)]
+    Maximum,
+}
+
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct RelayConfig {
+    #[n(0)]
+    pub pin: u8,
+    // The supply is on while the pin is low.
+    #[n(1)]
+    pub active_low: bool,
+    // Switched off once every pixel has been black this long.
+    #[n(2)]
+    pub off_after_s: u16,
+    // Time the strip needs after switching on before it takes data.
+    #[n(3)]
+    pub settle_ms: u16,
+}
+
+/// Where log records are mirrored to, besides the probe.
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct LogConfig {
+    // Records below this level are only logged locally.
+    #[n(0)]
+    pub level: Level,
+    #[n(1)]
+    pub sink: LogSink,
+    // Records sent per minute at most; the rest wait in a ring buffer.
+    #[n(2)]
+    pub rate: u16,
+    // Lowest level logged at all, for modules not listed in `modules`.
+    #[n(3)]
+    #[cbor(default)]
+    pub local: Level,
+    #[n(4)]
+    #[cbor(default)]
+    pub modules: Vec<ModuleLevel>,
+}
+
+/// The lowest level logged by one module, e.g. `led`.
+#[derive(Clone, Encode, Decode)]
+#[cbor(map)]
+pub struct ModuleLevel {
+    #[n(0)]
+    pub module: String,
+    #[n(1)]
+    pub level: Level,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: Level::Warn,
+            sink: LogSink::None,
+            rate: 60,
+            local: Level::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Encode, Decode)]
+pub enum LogSink {
+    #[n(0)]
+    None,
+    // `<topic>/log` on the broker.
+    #[n(1)]
+    Mqtt,
+    // A syslog server, by dotted IPv4 address or name.
+    #[n(2)]
+    Syslog {
+        #[n(0)]
+        host: String,
+        #[n(1)]
+        port: u16,
+    },
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            wifi: WifiConfig {
+                networks: match option_env!("SSID") {
+                    Some(ssid) => vec![Network {
+                        ssid: ssid.into(),
+                        password: option_env!("PASSWORD").unwrap_or_default().into(),
+                    }],
+                    None => Vec::new(),
+                },
+                max_failures: 3,
+            },
+            mqtt: MqttConfig {
+                host: "192.168.1.1".into(),
+                port: 1883,
+                client_id: "rust-mqtt-demo-client".into(),
+                topic: "el".into(),
+                discover: false,
+            },
+            strip: StripConfig {
+                leds: LEDS as u16,
+                brightness: 10,
+            },
+            effect: EffectConfig {
+                hue: 0,
+                sat: 255,
+                speed: 0,
+            },
+            net: NetConfig::default(),
+            realtime: RealtimeConfig::default(),
+            sync: SyncConfig::default(),
+            time: TimeConfig::default(),
+            schedules: Vec::new(),
+            presets: Vec::new(),
+            power_on: PowerOn::default(),
+            ota_version: 0,
+            log: LogConfig::default(),
+            power: PowerConfig::default(),
+        }
+    }
+}
+
+/// What `Config::validate` found out of bounds.
+#[derive(Debug, PartialEq, defmt::Format)]
+pub enum Invalid {
+    Ssid,
+    Password,
+    Networks,
+    Prefix,
+    Leds,
+}
+
+impl Config {
+    /// Checks the values the firmware cannot work with, before a
+    /// configuration from outside is accepted.
+    pub fn validate(&self) -> Result<(), Invalid> {
+        if self.wifi.networks.len() > MAX_NETWORKS {
+            return Err(Invalid::Networks);
+        }
+        for network in &self.wifi.networks {
+            if network.ssid.len() > MAX_SSID {
+                return Err(Invalid::Ssid);
+            }
+            if network.password.len() > MAX_PASSWORD {
+                return Err(Invalid::Password);
+            }
+        }
+        if self.net.ipv4.as_ref().is_some_and(|ip| ip.prefix > 32) {
+            return Err(Invalid::Prefix);
+        }
+        if self.strip.leds as usize > LEDS {
+            return Err(Invalid::Leds);
+        }
+        Ok(())
+    }
+}
+
+/// Brings a record written by an older firmware up to the current schema.
+pub fn migrate(version: u16, payload: &[u8]) -> Option<Config> {
+    match version {
+        1 => minicbor::decode::<v1::Config>(payload).ok().map(Into::into),
+        SCHEMA_VERSION => minicbor::decode(payload).ok(),
+        _ => None,
+    }
+}
+
+/// Schema 1 only knew a single Wi-Fi network.
+mod v1 {
+    use alloc::{string::String, vec, vec::Vec};
+    use minicbor::Decode;
+
+    use super::{
+        EffectConfig, LogConfig, MqttConfig, NetConfig, Network, PowerConfig, PowerOn,
+        RealtimeConfig, StripConfig, SyncConfig, TimeConfig, WifiConfig,
+    };
+
+    #[derive(Decode)]
+    #[cbor(map)]
+    pub struct Config {
+        #[n(0)]
+        wifi: Wifi,
+        #[n(1)]
+        mqtt: MqttConfig,
+        #[n(2)]
+        strip: StripConfig,
+        #[n(3)]
+        effect: EffectConfig,
+    }
+
+    #[derive(Decode)]
+    #[cbor(map)]
+    struct Wifi {
+        #[n(0)]
+        ssid: String,
+        #[n(1)]
+        password: String,
+    }
+
+    impl From<Config> for super::Config {
+        fn from(c: Config) -> Self {
+            super::Config {
+                wifi: WifiConfig {
+                    networks: vec![Network {
+                        ssid: c.wifi.ssid,
+                        password: c.wifi.password,
+                    }],
+                    max_failures: 3,
+                },
+                mqtt: c.mqtt,
+                strip: c.strip,
+                effect: c.effect,
+                net: NetConfig::default(),
+                realtime: RealtimeConfig::default(),
+                sync: SyncConfig::default(),
+                time: TimeConfig::default(),
+                schedules: Vec::new(),
+                presets: Vec::new(),
+                power_on: PowerOn::default(),
+                ota_version: 0,
+                log: LogConfig::default(),
+                power: PowerConfig::default(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, defmt::Format)]
+pub enum StoreError {
+    Flash,
+    Encode,
+    TooLarge,
+}
+
+const MAGIC: u32 = 0x4b4e_4957;
+const HEADER: u32 = 16;
+const EMPTY: u32 = 0xffff_ffff;
+
+/// Append-only record log over a flash partition.
+///
+/// Records are appended one after another inside a sector; when a sector is
+/// full the log moves on to the next one and erases it, so erases are spread
+/// evenly over the partition. The newest record with a valid CRC wins, which
+/// also means a torn write falls back to the previous record.
+///
+/// Layout of a record: magic, sequence number, schema version, payload
+/// length, CRC-32 of everything before it and of the payload, then the
+/// payload padded up to the flash word size.
+pub struct Store<F> {
+    flash: F,
+    sector: u32,
+    offset: u32,
+    seq: u32,
+    // Schema version and payload CRC of the newest valid record.
+    last: Option<(u16, u32)>,
+}
+
+impl<F: NorFlash> Store<F> {
+    pub fn new(flash: F) -> Self {
+        Store {
+            flash,
+            sector: 0,
+            offset: 0,
+            seq: 0,
+            last: None,
+        }
+    }
+
+    fn sector_size() -> u32 {
+        F::ERASE_SIZE as u32
+    }
+
+    fn sectors(&self) -> u32 {
+        self.flash.capacity() as u32 / Self::sector_size()
+    }
+
+    fn padded(len: u32) -> u32 {
+        let w = (F::WRITE_SIZE.max(F::READ_SIZE)) as u32;
+        len.div_ceil(w) * w
+    }
+
+    /// Scans the whole partition, returning the newest valid record and
+    /// leaving the write position right after the last record written.
+    pub fn load(&mut self) -> Result<Option<(u16, Vec<u8>)>, StoreError> {
+        let mut newest: Option<(u32, u32, u16, u16)> = None;
+        let mut tail: Option<(u32, u32, u32)> = None;
+
+        for sector in 0..self.sectors() {
+            let base = sector * Self::sector_size();
+            let mut offset = 0;
+            while offset + HEADER <= Self::sector_size() {
+                let mut header = [0u8; HEADER as usize];
+                self.flash
+                    .read(base + offset, &mut header)
+                    .map_err(|_| StoreError::Flash)?;
+                let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+                if word(0) == EMPTY {
+                    break;
+                }
+                if word(0) != MAGIC {
+                    // Garbage: treat the rest of the sector as used.
+                    offset = Self::sector_size();
+                    break;
+                }
+                let seq = word(4);
+                let version = u16::from_le_bytes([header[8], header[9]]);
+                let len = u16::from_le_bytes([header[10], header[11]]);
+                let crc = word(12);
+                let next = offset + HEADER + Self::padded(len as u32);
+                if next > Self::sector_size() {
+                    offset = Self::sector_size();
+                    break;
+                }
+
+                if newest.is_none_or(|(s, ..)| seq > s)
+                    && self.check(base + offset, &header, len, crc)?
+                {
+                    newest = Some((seq, base + offset, version, len));
+                }
+                if tail.is_none_or(|(s, ..)| seq > s) {
+                    tail = Some((seq, sector, next));
+                }
+                offset = next;
+            }
+            if let Some((_, s, end)) = &mut tail
+                && *s == sector
+            {
+                *end = offset.max(*end);
+            }
+        }
+
+        if let Some((seq, sector, offset)) = tail {
+            self.seq = seq;
+            self.sector = sector;
+            self.offset = offset;
+        }
+
+        match newest {
+            Some((_, address, version, len)) => {
+                let mut payload = vec![0u8; Self::padded(len as u32) as usize];
+                self.flash
+                    .read(address + HEADER, &mut payload)
+                    .map_err(|_| StoreError::Flash)?;
+                payload.truncate(len as usize);
+                self.last = Some((version, crc32(!0, &payload)));
+                Ok(Some((version, payload)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn check(
+        &mut self,
+        address: u32,
+        header: &[u8],
+        len: u16,
+        crc: u32,
+    ) -> Result<bool, StoreError> {
+        let mut payload = vec![0u8; Self::padded(len as u32) as usize];
+        self.flash
+            .read(address + HEADER, &mut payload)
+            .map_err(|_| StoreError::Flash)?;
+        Ok(record_crc(&header[..12], &payload[..len as usize]) == crc)
+    }
+
+    /// Appends a new record unless it matches the one already stored.
+    pub fn save(&mut self, version: u16, payload: &[u8]) -> Result<(), StoreError> {
+        let size = HEADER + Self::padded(payload.len() as u32);
+        if size > Self::sector_size() || self.sectors() < 2 {
+            return Err(StoreError::TooLarge);
+        }
+        let content = (version, crc32(!0, payload));
+        if self.last == Some(content) {
+            return Ok(());
+        }
+
+        let seq = self.seq.wrapping_add(1);
+        let mut record = vec![0xffu8; size as usize];
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..8].copy_from_slice(&seq.to_le_bytes());
+        record[8..10].copy_from_slice(&version.to_le_bytes());
+        record[10..12].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        let crc = record_crc(&record[..12], payload);
+        record[12..16].copy_from_slice(&crc.to_le_bytes());
+        record[16..16 + payload.len()].copy_from_slice(payload);
+
+        if self.seq == 0 || self.offset + size > Self::sector_size() {
+            self.sector = if self.seq == 0 {
+                0
+            } else {
+                (self.sector + 1) % self.sectors()
+            };
+            let base = self.sector * Self::sector_size();
+            self.flash
+                .erase(base, base + Self::sector_size())
+                .map_err(|_| StoreError::Flash)?;
+            self.offset = 0;
+        }
+
+        let address = self.sector * Self::sector_size() + self.offset;
+        self.flash
+            .write(address, &record)
+            .map_err(|_| StoreError::Flash)?;
+        self.offset += size;
+        self.seq = seq;
+        self.last = Some(content);
+        Ok(())
+    }
+}
+
+fn record_crc(header: &[u8], payload: &[u8]) -> u32 {
+    !crc32(crc32(!0, header), payload)
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+    use core::convert::Infallible;
+    use minicbor::{Encoder, encode};
+
+    use super::*;
+    use crate::ram_flash::{RamFlash, SECTOR};
+
+    fn reopen(flash: &mut RamFlash) -> (Store<&mut RamFlash>, Option<(u16, Vec<u8>)>) {
+        let mut store = Store::new(flash);
+        let loaded = store.load().unwrap();
+        (store, loaded)
+    }
+
+    #[test]
+    fn keeps_the_newest_record() {
+        let mut flash = RamFlash::new(2);
+        let (mut store, loaded) = reopen(&mut flash);
+        assert_eq!(loaded, None);
+        store.save(1, b"first").unwrap();
+        store.save(1, b"second").unwrap();
+        assert_eq!(reopen(&mut flash).1, Some((1, b"second".to_vec())));
+    }
+
+    #[test]
+    fn torn_payload_falls_back() {
+        let mut flash = RamFlash::new(2);
+        reopen(&mut flash).0.save(1, b"first").unwrap();
+        // The header makes it, but not all of the payload.
+        flash.power = Some(HEADER as usize + 4);
+        assert!(matches!(
+            reopen(&mut flash).0.save(1, b"second, longer"),
+            Err(StoreError::Flash)
+        ));
+        flash.power = None;
+
+        let (mut store, loaded) = reopen(&mut flash);
+        assert_eq!(loaded, Some((1, b"first".to_vec())));
+        store.save(1, b"third").unwrap();
+        assert_eq!(reopen(&mut flash).1, Some((1, b"third".to_vec())));
+    }
+
+    #[test]
+    fn torn_header_falls_back() {
+        let mut flash = RamFlash::new(2);
+        reopen(&mut flash).0.save(1, b"first").unwrap();
+        // Magic and sequence number only; the length still reads as erased.
+        flash.power = Some(8);
+        assert!(reopen(&mut flash).0.save(1, b"second").is_err());
+        flash.power = None;
+
+        let (mut store, loaded) = reopen(&mut flash);
+        assert_eq!(loaded, Some((1, b"first".to_vec())));
+        store.save(1, b"third").unwrap();
+        assert_eq!(reopen(&mut flash).1, Some((1, b"third".to_vec())));
+    }
+
+    #[test]
+    fn rotates_through_every_sector() {
+        let mut flash = RamFlash::new(3);
+        // Four records to a sector, so this goes round several times.
+        let payload = |i: usize| vec![i as u8; 1000];
+        for i in 0..40 {
+            let (mut store, loaded) = reopen(&mut flash);
+            if i > 0 {
+                assert_eq!(loaded, Some((1, payload(i - 1))));
+            }
+            store.save(1, &payload(i)).unwrap();
+        }
+        assert_eq!(reopen(&mut flash).1, Some((1, payload(39))));
+
+        let (min, max) = (flash.erases.iter().min(), flash.erases.iter().max());
+        assert!(*min.unwrap() >= 3);
+        assert!(max.unwrap() - min.unwrap() <= 1);
+    }
+
+    #[test]
+    fn unchanged_records_are_not_written() {
+        let mut flash = RamFlash::new(2);
+        let (mut store, _) = reopen(&mut flash);
+        store.save(1, b"same").unwrap();
+        let before = store.offset;
+        store.save(1, b"same").unwrap();
+        assert_eq!(store.offset, before);
+    }
+
+    #[test]
+    fn too_large() {
+        let mut flash = RamFlash::new(2);
+        let (mut store, _) = reopen(&mut flash);
+        let payload = vec![0; SECTOR - HEADER as usize + 1];
+        assert!(matches!(store.save(1, &payload), Err(StoreError::TooLarge)));
+        store.save(1, &payload[1..]).unwrap();
+
+        // Nowhere to go while the only sector is erased.
+        let mut flash = RamFlash::new(1);
+        assert!(matches!(
+            reopen(&mut flash).0.save(1, b"small"),
+            Err(StoreError::TooLarge)
+        ));
+    }
+
+    // A record as the firmware with schema 1 wrote it.
+    fn v1_payload() -> Result<Vec<u8>, encode::Error<Infallible>> {
+        let mut e = Encoder::new(Vec::new());
+        e.map(4)?;
+        e.u8(0)?.map(2)?;
+        e.u8(0)?.str("home")?;
+        e.u8(1)?.str("secret")?;
+        e.u8(1)?.map(4)?;
+        e.u8(0)?.str("10.0.0.2")?;
+        e.u8(1)?.u16(1883)?;
+        e.u8(2)?.str("hall")?;
+        e.u8(3)?.str("el")?;
+        e.u8(2)?.map(2)?;
+        e.u8(0)?.u16(30)?;
+        e.u8(1)?.u8(40)?;
+        e.u8(3)?.map(2)?;
+        e.u8(0)?.u8(10)?;
+        e.u8(1)?.u8(200)?;
+        Ok(e.into_writer())
+    }
+
+    #[test]
+    fn migrates_v1() {
+        let mut flash = RamFlash::new(2);
+        reopen(&mut flash)
+            .0
+            .save(1, &v1_payload().unwrap())
+            .unwrap();
+        let (version, payload) = reopen(&mut flash).1.unwrap();
+        assert_eq!(version, 1);
+
+        let config = migrate(version, &payload).unwrap();
+        let [network] = &config.wifi.networks[..] else {
+            panic!("expected one network");
+        };
+        assert_eq!(
+            (network.ssid.as_str(), network.password.as_str()),
+            ("home", "secret")
+        );
+        assert_eq!(config.wifi.max_failures, 3);
+        assert_eq!((config.mqtt.host.as_str(), config.mqtt.port), ("10.0.0.2", 1883));
+        assert_eq!(config.mqtt.client_id, "hall");
+        assert_eq!((config.strip.leds, config.strip.brightness), (30, 40));
+        assert_eq!((config.effect.hue, config.effect.sat), (10, 200));
+        // Added after schema 1, so filled in by their defaults.
+        assert!(!config.mqtt.discover);
+        assert_eq!(config.effect.speed, 0);
+        assert!(config.schedules.is_empty());
+        assert!(matches!(config.log.sink, LogSink::None));
+    }
+
+    fn network(ssid: &str, password: &str) -> Network {
+        Network {
+            ssid: ssid.into(),
+            password: password.into(),
+        }
+    }
+
+    #[test]
+    fn validates_networks() {
+        let mut config = Config::default();
+        config.wifi.networks = vec![network(&"s".repeat(MAX_SSID), &"p".repeat(MAX_PASSWORD))];
+        assert_eq!(config.validate(), Ok(()));
+
+        config.wifi.networks = vec![network(&"s".repeat(MAX_SSID + 1), "")];
+        assert_eq!(config.validate(), Err(Invalid::Ssid));
+        config.wifi.networks = vec![network("home", &"p".repeat(MAX_PASSWORD + 1))];
+        assert_eq!(config.validate(), Err(Invalid::Password));
+
+        config.wifi.networks = vec![network("home", ""); MAX_NETWORKS];
+        assert_eq!(config.validate(), Ok(()));
+        config.wifi.networks.push(network("away", ""));
+        assert_eq!(config.validate(), Err(Invalid::Networks));
+    }
+
+    #[test]
+    fn validates_prefix_and_strip() {
+        let mut config = Config::default();
+        config.wifi.networks.clear();
+        config.net.ipv4 = Some(StaticIpv4 {
+            address: [192, 168, 1, 20],
+            prefix: 32,
+            gateway: None,
+            dns: Vec::new(),
+        });
+        assert_eq!(config.validate(), Ok(()));
+        config.net.ipv4.as_mut().unwrap().prefix = 33;
+        assert_eq!(config.validate(), Err(Invalid::Prefix));
+        config.net.ipv4 = None;
+
+        config.strip.leds = LEDS as u16;
+        assert_eq!(config.validate(), Ok(()));
+        config.strip.leds += 1;
+        assert_eq!(config.validate(), Err(Invalid::Leds));
+    }
+
+    #[test]
+    fn migrate_rejects_unknown_versions() {
+        let payload = minicbor::to_vec(Config::default()).unwrap();
+        assert!(migrate(SCHEMA_VERSION, &payload).is_some());
+        assert!(migrate(SCHEMA_VERSION + 1, &payload).is_none());
+        assert!(migrate(SCHEMA_VERSION, b"\xff").is_none());
+    }
+}
@@ -0,0 +1,15 @@
+//! The parts of the firmware that do not touch the hardware, kept apart so
+//! that they build and are tested on the host.
+#![no_std]
+extern crate alloc;
+
+pub mod clock;
+pub mod config;
+pub mod log;
+pub mod ota;
+pub mod power;
+pub mod schedule;
+pub mod sun;
+
+#[cfg(test)]
+mod ram_flash;
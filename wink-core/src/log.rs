@@ -0,0 +1,27 @@
+use minicbor::{Decode, Encode};
+
+#[derive(Clone, Copy, Default, PartialEq, PartialOrd, Encode, Decode, defmt::Format)]
+#[cbor(index_only)]
+pub enum Level {
+    #[n(0)]
+    Debug,
+    #[default]
+    #[n(1)]
+    Info,
+    #[n(2)]
+    Warn,
+    #[n(3)]
+    Error,
+}
+
+impl Level {
+    /// The syslog severity of records at this level.
+    pub fn severity(self) -> u8 {
+        match self {
+            Level::Debug => 7,
+            Level::Info => 6,
+            Level::Warn => 4,
+            Level::Error => 3,
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Checking and writing firmware images; booting them is left to the
+//! firmware.
+use alloc::{string::String, vec::Vec};
+use ed25519_dalek::{Signature, VerifyingKey};
+use embedded_storage::nor_flash::NorFlash;
+use minicbor::Decode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    // No OTA data partition, or fewer than two app slots.
+    NoSlot,
+    Flash,
+    Manifest,
+    TooLarge,
+    // A chunk that does not continue where the previous one ended.
+    OutOfOrder,
+    Incomplete,
+    Digest,
+    Signature,
+    // Older than the last image installed.
+    Downgrade,
+    Network,
+    Http,
+}
+
+/// What the sender announces before an image.
+#[derive(Decode, Deserialize)]
+#[cbor(map)]
+pub struct Manifest {
+    #[n(0)]
+    pub size: u32,
+    // Hex encoded.
+    #[n(1)]
+    pub sha256: String,
+    // Where to fetch the image from over HTTP; without one, it follows in
+    // chunks over MQTT.
+    #[n(2)]
+    #[serde(default)]
+    pub url: Option<String>,
+    // Only ever increases from one image to the next.
+    #[n(3)]
+    pub version: u32,
+    // Hex Ed25519 signature over the digest, version and size.
+    #[n(4)]
+    pub signature: String,
+}
+
+impl Manifest {
+    fn digest(&self) -> Option<[u8; 32]> {
+        from_hex(&self.sha256)
+    }
+
+    /// Checks that the manifest is signed with `key` and announces an image
+    /// no older than `min_version`. The digest then vouches for the image.
+    pub fn verify(&self, key: &[u8; 32], min_version: u32) -> Result<(), Error> {
+        let digest = self.digest().ok_or(Error::Manifest)?;
+        let signature = from_hex(&self.signature).ok_or(Error::Signature)?;
+        let mut signed = [0u8; 40];
+        signed[..32].copy_from_slice(&digest);
+        signed[32..36].copy_from_slice(&self.version.to_be_bytes());
+        signed[36..].copy_from_slice(&self.size.to_be_bytes());
+        VerifyingKey::from_bytes(key)
+            .and_then(|key| key.verify_strict(&signed, &Signature::from_bytes(&signature)))
+            .map_err(|_| Error::Signature)?;
+        if self.version < min_version {
+            return Err(Error::Downgrade);
+        }
+        Ok(())
+    }
+}
+
+/// Decodes exactly `N` bytes of hex.
+pub fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_chunks::<2>().0) {
+        let nibble = |c: u8| (c as char).to_digit(16);
+        *byte = (nibble(pair[0])? << 4 | nibble(pair[1])?) as u8;
+    }
+    Some(bytes)
+}
+
+/// One piece of an image sent over MQTT.
+#[derive(Decode)]
+#[cbor(map)]
+pub struct Chunk<'a> {
+    #[n(0)]
+    pub offset: u32,
+    #[b(1)]
+    #[cbor(with = "minicbor::bytes")]
+    pub data: &'a [u8],
+}
+
+/// An image being written to a slot, checked against its manifest once
+/// complete. Sectors are erased just ahead of the data.
+pub struct Update<F> {
+    flash: F,
+    size: u32,
+    digest: [u8; 32],
+    sha: Sha256,
+    received: u32,
+    written: u32,
+    erased: u32,
+    // The tail that does not fill a flash word yet.
+    pending: Vec<u8>,
+}
+
+impl<F: NorFlash> Update<F> {
+    pub fn new(flash: F, manifest: &Manifest) -> Result<Self, Error> {
+        let digest = manifest.digest().ok_or(Error::Manifest)?;
+        if manifest.size as usize > flash.capacity() {
+            return Err(Error::TooLarge);
+        }
+        Ok(Update {
+            flash,
+            size: manifest.size,
+            digest,
+            sha: Sha256::new(),
+            received: 0,
+            written: 0,
+            erased: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    /// How much of the image has arrived.
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.size
+    }
+
+    /// Appends the data at `offset`. Data received before is skipped, so
+    /// that redelivered messages do no harm.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error> {
+        if offset > self.received {
+            return Err(Error::OutOfOrder);
+        }
+        let Some(data) = data.get((self.received - offset) as usize..) else {
+            return Ok(());
+        };
+        if self.received as usize + data.len() > self.size as usize {
+            return Err(Error::TooLarge);
+        }
+        self.sha.update(data);
+        self.received += data.len() as u32;
+        self.pending.extend_from_slice(data);
+        let whole = self.pending.len() / F::WRITE_SIZE * F::WRITE_SIZE;
+        self.program(whole)
+    }
+
+    /// Checks that the image is complete and intact.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if !self.is_complete() {
+            return Err(Error::Incomplete);
+        }
+        let padded = self.pending.len().next_multiple_of(F::WRITE_SIZE);
+        self.pending.resize(padded, 0xff);
+        self.program(padded)?;
+        if self.sha.finalize().as_slice() != self.digest {
+            return Err(Error::Digest);
+        }
+        Ok(())
+    }
+
+    fn program(&mut self, len: usize) -> Result<(), Error> {
+        let end = self.written + len as u32;
+        while self.erased < end {
+            let to = self.erased + F::ERASE_SIZE as u32;
+            self.flash
+                .erase(self.erased, to)
+                .map_err(|_| Error::Flash)?;
+            self.erased = to;
+        }
+        self.flash
+            .write(self.written, &self.pending[..len])
+            .map_err(|_| Error::Flash)?;
+        self.written = end;
+        self.pending.drain(..len);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::String, vec::Vec};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+    use crate::ram_flash::{RamFlash, SECTOR};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn manifest(image: &[u8], version: u32) -> Manifest {
+        let digest = Sha256::digest(image);
+        let mut signed = Vec::new();
+        signed.extend_from_slice(&digest);
+        signed.extend_from_slice(&version.to_be_bytes());
+        signed.extend_from_slice(&(image.len() as u32).to_be_bytes());
+        let signature = SigningKey::from_bytes(&KEY).sign(&signed);
+        Manifest {
+            size: image.len() as u32,
+            sha256: hex(&digest),
+            url: None,
+            version,
+            signature: hex(&signature.to_bytes()),
+        }
+    }
+
+    fn public_key() -> [u8; 32] {
+        SigningKey::from_bytes(&KEY).verifying_key().to_bytes()
+    }
+
+    #[test]
+    fn writes_an_image_in_chunks() {
+        // Neither a whole number of sectors nor of flash words.
+        let image = image(SECTOR + 1001);
+        let mut flash = RamFlash::new(4);
+        let mut update = Update::new(&mut flash, &manifest(&image, 1)).unwrap();
+        for (i, chunk) in image.chunks(333).enumerate() {
+            update.write((i * 333) as u32, chunk).unwrap();
+        }
+        assert!(update.is_complete());
+        update.finish().unwrap();
+        assert_eq!(flash.data[..image.len()], image);
+        // Only the sectors the image needs.
+        assert_eq!(flash.erases, [1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn skips_chunks_received_twice() {
+        let image = image(1000);
+        let mut flash = RamFlash::new(1);
+        let mut update = Update::new(&mut flash, &manifest(&image, 1)).unwrap();
+        update.write(0, &image[..400]).unwrap();
+        update.write(0, &image[..400]).unwrap();
+        // Partly received before.
+        update.write(300, &image[300..700]).unwrap();
+        update.write(700, &image[700..]).unwrap();
+        update.write(700, &image[700..]).unwrap();
+        update.finish().unwrap();
+        assert_eq!(flash.data[..image.len()], image);
+    }
+
+    #[test]
+    fn rejects_chunks_out_of_order() {
+        let image = image(1000);
+        let mut flash = RamFlash::new(1);
+        let mut update = Update::new(&mut flash, &manifest(&image, 1)).unwrap();
+        update.write(0, &image[..400]).unwrap();
+        assert!(matches!(
+            update.write(800, &image[800..]),
+            Err(Error::OutOfOrder)
+        ));
+        assert_eq!(update.received(), 400);
+        assert!(matches!(update.finish(), Err(Error::Incomplete)));
+    }
+
+    #[test]
+    fn rejects_images_too_large() {
+        // Announced larger than the slot.
+        let image = image(SECTOR + 1);
+        let mut flash = RamFlash::new(1);
+        assert!(matches!(
+            Update::new(&mut flash, &manifest(&image, 1)),
+            Err(Error::TooLarge)
+        ));
+
+        // Larger than announced.
+        let mut update = Update::new(&mut flash, &manifest(&image[..100], 1)).unwrap();
+        assert!(matches!(
+            update.write(0, &image[..101]),
+            Err(Error::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_digest_mismatch() {
+        let image = image(1000);
+        let mut corrupt = image.clone();
+        corrupt[500] ^= 1;
+        let mut flash = RamFlash::new(1);
+        let mut update = Update::new(&mut flash, &manifest(&image, 1)).unwrap();
+        update.write(0, &corrupt).unwrap();
+        assert!(matches!(update.finish(), Err(Error::Digest)));
+    }
+
+    #[test]
+    fn verifies_the_signature() {
+        let image = image(1000);
+        let key = public_key();
+        manifest(&image, 3).verify(&key, 3).unwrap();
+
+        // Each signed field changed after signing.
+        let mut m = manifest(&image, 3);
+        m.version = 4;
+        assert!(matches!(m.verify(&key, 3), Err(Error::Signature)));
+        let mut m = manifest(&image, 3);
+        m.size += 1;
+        assert!(matches!(m.verify(&key, 3), Err(Error::Signature)));
+        let mut m = manifest(&image, 3);
+        m.sha256 = manifest(&image[1..], 3).sha256;
+        assert!(matches!(m.verify(&key, 3), Err(Error::Signature)));
+
+        // Signed with another key, or not a signature at all.
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes();
+        assert!(matches!(
+            manifest(&image, 3).verify(&other, 3),
+            Err(Error::Signature)
+        ));
+        let mut m = manifest(&image, 3);
+        m.signature.truncate(10);
+        assert!(matches!(m.verify(&key, 3), Err(Error::Signature)));
+        let mut m = manifest(&image, 3);
+        m.sha256 = "not hex".into();
+        assert!(matches!(m.verify(&key, 3), Err(Error::Manifest)));
+    }
+
+    #[test]
+    fn rejects_downgrades() {
+        let image = image(1000);
+        let key = public_key();
+        assert!(matches!(
+            manifest(&image, 2).verify(&key, 3),
+            Err(Error::Downgrade)
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+//! Keeping the strip's draw within what its supply delivers.
+use smart_leds_trait::RGB8;
+
+use crate::config::CurrentLimit;
+
+/// Estimated draw in mA of `pixels` once scaled to `brightness` the way
+/// `smart_leds::brightness` does, assuming channels draw in proportion to
+/// their level.
+pub fn estimate_ma(pixels: &[RGB8], brightness: u8, limit: &CurrentLimit) -> u32 {
+    let scaled = levels(pixels) * (brightness as u64 + 1) / 256;
+    let idle = limit.idle_ma as u64 * pixels.len() as u64;
+    (idle + scaled * limit.channel_ma as u64 / 255) as u32
+}
+
+/// The highest brightness up to `brightness` at which `pixels` stay within
+/// the budget, or 0 if none does.
+pub fn limit_brightness(pixels: &[RGB8], brightness: u8, limit: &CurrentLimit) -> u8 {
+    let levels = levels(pixels);
+    let idle = limit.idle_ma as u64 * pixels.len() as u64;
+    let Some(available) = (limit.budget_ma as u64).checked_sub(idle) else {
+        return 0;
+    };
+    if levels == 0 || limit.channel_ma == 0 {
+        return brightness;
+    }
+    // The largest b for which levels * (b + 1) / 256 * channel_ma / 255 is
+    // still within what is available.
+    let steps = available * 256 * 255 / (levels * limit.channel_ma as u64);
+    steps.saturating_sub(1).min(brightness as u64) as u8
+}
+
+fn levels(pixels: &[RGB8]) -> u64 {
+    pixels
+        .iter()
+        .map(|p| p.r as u64 + p.g as u64 + p.b as u64)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: RGB8 = RGB8::new(255, 255, 255);
+
+    // WS2812B pixels on a supply good for `budget_ma`.
+    fn limit(budget_ma: u32) -> CurrentLimit {
+        CurrentLimit {
+            budget_ma,
+            channel_ma: 20,
+            idle_ma: 1,
+        }
+    }
+
+    #[test]
+    fn empty_frame() {
+        assert_eq!(estimate_ma(&[], 255, &limit(0)), 0);
+        assert_eq!(limit_brightness(&[], 200, &limit(0)), 200);
+    }
+
+    #[test]
+    fn black_frame_draws_idle_current() {
+        let pixels = [RGB8::default(); 50];
+        assert_eq!(estimate_ma(&pixels, 255, &limit(5000)), 50);
+        assert_eq!(limit_brightness(&pixels, 255, &limit(5000)), 255);
+    }
+
+    #[test]
+    fn idle_current_over_budget() {
+        let pixels = [RGB8::default(); 50];
+        assert_eq!(limit_brightness(&pixels, 255, &limit(49)), 0);
+        assert_eq!(limit_brightness(&[WHITE; 50], 255, &limit(49)), 0);
+        // Exactly the idle current leaves nothing to light them with.
+        assert_eq!(limit_brightness(&[WHITE; 50], 255, &limit(50)), 0);
+    }
+
+    #[test]
+    fn full_white_is_clamped_to_the_budget() {
+        let pixels = [WHITE; 50];
+        let limit = limit(1000);
+        // 50 pixels of three channels at 20 mA, plus 1 mA each.
+        assert_eq!(estimate_ma(&pixels, 255, &limit), 3050);
+        let brightness = limit_brightness(&pixels, 255, &limit);
+        assert!(estimate_ma(&pixels, brightness, &limit) <= 1000);
+        // And no dimmer than it has to be.
+        assert!(estimate_ma(&pixels, brightness + 1, &limit) > 1000);
+    }
+
+    #[test]
+    fn brightness_is_never_raised() {
+        let frames: [&[RGB8]; 4] = [
+            &[],
+            &[RGB8::default(); 10],
+            &[RGB8::new(255, 0, 0); 10],
+            &[WHITE; 200],
+        ];
+        for pixels in frames {
+            for budget in [0, 100, 1000, 100_000] {
+                for brightness in [0, 1, 64, 128, 255] {
+                    let limited = limit_brightness(pixels, brightness, &limit(budget));
+                    assert!(limited <= brightness);
+                }
+            }
+        }
+        // With power to spare, the brightness asked for.
+        assert_eq!(limit_brightness(&[WHITE; 50], 100, &limit(100_000)), 100);
+    }
+}
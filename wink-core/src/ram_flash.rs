@@ -0,0 +1,99 @@
+//! NOR flash in RAM for tests. Like the real thing, erasing sets every bit
+//! of a sector and writing can only clear bits; power can also be cut in
+//! the middle of a write.
+use alloc::{vec, vec::Vec};
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+pub const SECTOR: usize = 4096;
+
+#[derive(Debug)]
+pub struct Error(NorFlashErrorKind);
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        self.0
+    }
+}
+
+pub struct RamFlash {
+    pub data: Vec<u8>,
+    // Times each sector was erased.
+    pub erases: Vec<u32>,
+    // Bytes written before the power fails, if it is going to.
+    pub power: Option<usize>,
+}
+
+impl RamFlash {
+    /// A flash of `sectors` erased sectors.
+    pub fn new(sectors: usize) -> Self {
+        RamFlash {
+            data: vec![0xff; sectors * SECTOR],
+            erases: vec![0; sectors],
+            power: None,
+        }
+    }
+
+    fn check(&self, offset: u32, len: usize, align: usize) -> Result<usize, Error> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(Error(NorFlashErrorKind::NotAligned));
+        }
+        if offset + len > self.data.len() {
+            return Err(Error(NorFlashErrorKind::OutOfBounds));
+        }
+        Ok(offset)
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 4;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let from = self.check(from, (to - from) as usize, SECTOR)?;
+        self.data[from..to as usize].fill(0xff);
+        for sector in from / SECTOR..to as usize / SECTOR {
+            self.erases[sector] += 1;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        let len = match &mut self.power {
+            Some(left) => {
+                let len = bytes.len().min(*left);
+                *left -= len;
+                len
+            }
+            None => bytes.len(),
+        };
+        for (cell, byte) in self.data[offset..].iter_mut().zip(&bytes[..len]) {
+            *cell &= byte;
+        }
+        if len < bytes.len() {
+            return Err(Error(NorFlashErrorKind::Other));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use alloc::vec::Vec;
+
+use crate::clock::DateTime;
+use crate::config::{Anchor, Location, Schedule};
+use crate::sun;
+
+// Minutes missed that are still made up for, enough to cover the hour
+// skipped when daylight saving time starts.
+const CATCH_UP: i64 = 60;
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// Time source, so that the scheduler can run off any clock.
+pub trait Clock {
+    /// Seconds since the Unix epoch, if known.
+    fn utc(&self) -> Option<i64>;
+    /// Shifts a Unix time into local time.
+    fn to_local(&self, utc: i64) -> i64;
+}
+
+/// Finds the schedules that became due between polls.
+#[derive(Default)]
+pub struct Scheduler {
+    // The last local minute looked at.
+    last: Option<i64>,
+}
+
+impl Scheduler {
+    /// Schedules due since the previous poll, in order. Minutes are only
+    /// looked at once, so nothing repeats when the clock is set back.
+    pub fn poll<'a>(
+        &mut self,
+        clock: &impl Clock,
+        location: Option<&Location>,
+        schedules: &'a [Schedule],
+    ) -> Vec<&'a Schedule> {
+        let Some(now) = clock.utc().map(|t| clock.to_local(t).div_euclid(60)) else {
+            return Vec::new();
+        };
+        let from = match self.last {
+            Some(last) if last >= now => return Vec::new(),
+            Some(last) => (last + 1).max(now - CATCH_UP),
+            None => now,
+        };
+        self.last = Some(now);
+        (from..=now)
+            .flat_map(|minute| {
+                schedules
+                    .iter()
+                    .filter(move |s| s.due(minute, clock, location))
+            })
+            .collect()
+    }
+}
+
+impl Schedule {
+    /// Whether this is due at the given local minute since the epoch.
+    fn due(&self, minute: i64, clock: &impl Clock, location: Option<&Location>) -> bool {
+        // Offsets may reach into the day before or after the anchor.
+        let day = minute.div_euclid(MINUTES_PER_DAY);
+        (day - 1..=day + 1).any(|day| {
+            let weekday = DateTime::from_unix(day * 86_400).weekday;
+            self.days & 1 << weekday != 0
+                && self
+                    .anchor(day, clock, location)
+                    .is_some_and(|anchor| anchor + self.minute as i64 == minute)
+        })
+    }
+
+    /// Local minute since the epoch of the anchor on `day`, if there is one.
+    fn anchor(&self, day: i64, clock: &impl Clock, location: Option<&Location>) -> Option<i64> {
+        let sun = |pick: fn((i64, i64)) -> i64| {
+            let location = location?;
+            let times = sun::sun_times(day, location.latitude, location.longitude)?;
+            Some(clock.to_local(pick(times)).div_euclid(60))
+        };
+        match self.anchor {
+            Anchor::Midnight => Some(day * MINUTES_PER_DAY),
+            Anchor::Sunrise => sun(|(rise, _)| rise),
+            Anchor::Sunset => sun(|(_, set)| set),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+    use crate::clock::{days_from_civil, to_local};
+    use crate::config::{Action, DstRule, TimeConfig};
+
+    struct TestClock {
+        utc: Option<i64>,
+        zone: TimeConfig,
+    }
+
+    impl Clock for TestClock {
+        fn utc(&self) -> Option<i64> {
+            self.utc
+        }
+
+        fn to_local(&self, utc: i64) -> i64 {
+            to_local(&self.zone, utc)
+        }
+    }
+
+    fn utc_zone() -> TimeConfig {
+        TimeConfig {
+            servers: Vec::new(),
+            offset_min: 0,
+            dst: None,
+            location: None,
+        }
+    }
+
+    // Central European time.
+    fn cet_zone() -> TimeConfig {
+        TimeConfig {
+            offset_min: 60,
+            dst: Some(DstRule {
+                start_month: 3,
+                start_week: 5,
+                start_hour: 2,
+                end_month: 10,
+                end_week: 5,
+                end_hour: 2,
+                weekday: 0,
+                save_min: 60,
+            }),
+            ..utc_zone()
+        }
+    }
+
+    fn at(year: i32, month: u8, day: u8, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60
+    }
+
+    fn schedule(days: u8, minute: i16) -> Schedule {
+        Schedule {
+            days,
+            minute,
+            action: Action::On,
+            fade_s: 0,
+            anchor: Anchor::Midnight,
+        }
+    }
+
+    fn daily(hour: i16, minute: i16) -> Schedule {
+        schedule(0x7f, hour * 60 + minute)
+    }
+
+    /// Polls at each of `times`, returning when and which schedules fired.
+    fn run(
+        scheduler: &mut Scheduler,
+        zone: TimeConfig,
+        schedules: &[Schedule],
+        times: impl IntoIterator<Item = i64>,
+    ) -> Vec<(i64, usize)> {
+        let mut clock = TestClock { utc: None, zone };
+        let mut fired = Vec::new();
+        for t in times {
+            clock.utc = Some(t);
+            for s in scheduler.poll(&clock, None, schedules) {
+                let index = schedules.iter().position(|x| core::ptr::eq(x, s));
+                fired.push((t, index.unwrap()));
+            }
+        }
+        fired
+    }
+
+    // Every ten seconds from `from` up to `to`, like the firmware polls.
+    fn every_poll(from: i64, to: i64) -> impl Iterator<Item = i64> {
+        (from..to).step_by(10)
+    }
+
+    #[test]
+    fn nothing_without_time() {
+        let mut scheduler = Scheduler::default();
+        let clock = TestClock {
+            utc: None,
+            zone: utc_zone(),
+        };
+        assert!(scheduler.poll(&clock, None, &[daily(0, 0)]).is_empty());
+    }
+
+    #[test]
+    fn catches_up_after_a_sleep() {
+        let schedules = [daily(7, 0), daily(7, 20), daily(9, 0)];
+        let mut scheduler = Scheduler::default();
+        let fired = run(
+            &mut scheduler,
+            utc_zone(),
+            &schedules,
+            [at(2024, 5, 6, 6, 58), at(2024, 5, 6, 7, 30)],
+        );
+        let woke = at(2024, 5, 6, 7, 30);
+        assert_eq!(fired, vec![(woke, 0), (woke, 1)]);
+
+        // Asleep for longer than is made up for.
+        let fired = run(
+            &mut scheduler,
+            utc_zone(),
+            &schedules,
+            [at(2024, 5, 6, 10, 30)],
+        );
+        assert_eq!(fired, vec![]);
+    }
+
+    #[test]
+    fn setting_the_clock_back_does_not_repeat() {
+        let schedules = [daily(7, 0)];
+        let mut scheduler = Scheduler::default();
+        let fired = run(
+            &mut scheduler,
+            utc_zone(),
+            &schedules,
+            [
+                at(2024, 5, 6, 6, 59),
+                at(2024, 5, 6, 7, 1),
+                // Set back by a quarter of an hour.
+                at(2024, 5, 6, 6, 46),
+                at(2024, 5, 6, 6, 59),
+                at(2024, 5, 6, 7, 0),
+                at(2024, 5, 6, 7, 2),
+                at(2024, 5, 7, 7, 0),
+            ],
+        );
+        assert_eq!(
+            fired,
+            vec![(at(2024, 5, 6, 7, 1), 0), (at(2024, 5, 7, 7, 0), 0)]
+        );
+    }
+
+    #[test]
+    fn runs_schedules_skipped_by_spring_forward() {
+        // 02:30 does not exist on that day; clocks go from 02:00 to 03:00.
+        let schedules = [daily(2, 30), daily(3, 15)];
+        let mut scheduler = Scheduler::default();
+        let fired = run(
+            &mut scheduler,
+            cet_zone(),
+            &schedules,
+            every_poll(at(2024, 3, 30, 23, 0), at(2024, 3, 31, 3, 0)),
+        );
+        // 03:00 and 03:15 summer time.
+        assert_eq!(
+            fired,
+            vec![(at(2024, 3, 31, 1, 0), 0), (at(2024, 3, 31, 1, 15), 1)]
+        );
+    }
+
+    #[test]
+    fn runs_schedules_in_the_repeated_hour_once() {
+        // From 03:00 summer time back to 02:00.
+        let schedules = [daily(2, 30)];
+        let mut scheduler = Scheduler::default();
+        let fired = run(
+            &mut scheduler,
+            cet_zone(),
+            &schedules,
+            every_poll(at(2024, 10, 26, 23, 0), at(2024, 10, 27, 3, 0)),
+        );
+        assert_eq!(fired, vec![(at(2024, 10, 27, 0, 30), 0)]);
+    }
+
+    #[test]
+    fn keeps_to_the_weekdays() {
+        const MONDAY_TO_FRIDAY: u8 = 0b011_1110;
+        const SUNDAY: u8 = 0b000_0001;
+        // Half an hour before Sunday starts, so late on Saturday.
+        let schedules = [schedule(MONDAY_TO_FRIDAY, 7 * 60), schedule(SUNDAY, -30)];
+        let mut scheduler = Scheduler::default();
+        // 2024-01-01 was a Monday.
+        let fired = run(
+            &mut scheduler,
+            utc_zone(),
+            &schedules,
+            (at(2024, 1, 1, 0, 0)..at(2024, 1, 8, 0, 0)).step_by(300),
+        );
+        let weekdays: Vec<_> = fired
+            .iter()
+            .map(|(t, i)| (DateTime::from_unix(*t).weekday, *i))
+            .collect();
+        assert_eq!(
+            weekdays,
+            vec![(1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 1)]
+        );
+        assert_eq!(fired[5].0, at(2024, 1, 6, 23, 30));
+    }
+}
@@ -0,0 +1,129 @@
+use libm::{acos, asin, cos, sin};
+
+// Julian dates of the Unix epoch and of J2000.0.
+const UNIX_EPOCH: f64 = 2_440_587.5;
+const J2000: f64 = 2_451_545.0;
+// Altitude of the sun's centre at sunrise, for its radius and refraction.
+const HORIZON: f64 = -0.833;
+const OBLIQUITY: f64 = 23.4397;
+
+/// Sunrise and sunset in Unix seconds on the day `days` after the epoch, at
+/// latitude and longitude in degrees north and east. `None` when the sun
+/// stays up or down all day.
+///
+/// This is the sunrise equation with the approximations NOAA uses, good to a
+/// minute or so outside the polar regions.
+pub fn sun_times(days: i64, latitude: f32, longitude: f32) -> Option<(i64, i64)> {
+    let (latitude, longitude) = (latitude as f64, longitude as f64);
+    // Mean solar noon, in days since J2000.0.
+    let noon = (days as f64 + UNIX_EPOCH + 0.5 - J2000) - longitude / 360.0;
+    // Angles are left unreduced; only their sines and cosines are used.
+    let anomaly = (357.5291 + 0.985_600_28 * noon).to_radians();
+    let center = 1.9148 * sin(anomaly) + 0.02 * sin(2.0 * anomaly) + 0.0003 * sin(3.0 * anomaly);
+    let ecliptic = (anomaly.to_degrees() + center + 180.0 + 102.9372).to_radians();
+    let transit = noon + 0.0053 * sin(anomaly) - 0.0069 * sin(2.0 * ecliptic);
+
+    let declination = asin(sin(ecliptic) * sin(OBLIQUITY.to_radians()));
+    let latitude = latitude.to_radians();
+    let cos_hour = (sin(HORIZON.to_radians()) - sin(latitude) * sin(declination))
+        / (cos(latitude) * cos(declination));
+    if !(-1.0..=1.0).contains(&cos_hour) {
+        return None;
+    }
+    let hour = acos(cos_hour).to_degrees() / 360.0;
+
+    let unix = |j: f64| ((j + J2000 - UNIX_EPOCH) * 86_400.0) as i64;
+    Some((unix(transit - hour), unix(transit + hour)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::days_from_civil;
+
+    // Published tables round to the minute; the equation is good to about one.
+    const TOLERANCE: i64 = 120;
+
+    fn at(year: i32, month: u8, day: u8, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60
+    }
+
+    fn check(date: (i32, u8, u8), latitude: f32, longitude: f32, rise: i64, set: i64) {
+        let (year, month, day) = date;
+        let (r, s) = sun_times(days_from_civil(year, month, day), latitude, longitude).unwrap();
+        assert!(
+            (r - rise).abs() <= TOLERANCE,
+            "sunrise off by {} s",
+            r - rise
+        );
+        assert!((s - set).abs() <= TOLERANCE, "sunset off by {} s", s - set);
+    }
+
+    // The times below are from sunrise tables, converted to UTC.
+
+    #[test]
+    fn london() {
+        let (latitude, longitude) = (51.5074, -0.1278);
+        check(
+            (2024, 6, 20),
+            latitude,
+            longitude,
+            at(2024, 6, 20, 3, 43),
+            at(2024, 6, 20, 20, 21),
+        );
+        check(
+            (2024, 12, 21),
+            latitude,
+            longitude,
+            at(2024, 12, 21, 8, 4),
+            at(2024, 12, 21, 15, 53),
+        );
+    }
+
+    #[test]
+    fn sydney() {
+        // The sun rises on the previous day in UTC.
+        check(
+            (2024, 12, 21),
+            -33.8688,
+            151.2093,
+            at(2024, 12, 20, 18, 41),
+            at(2024, 12, 21, 9, 5),
+        );
+    }
+
+    #[test]
+    fn singapore() {
+        check(
+            (2024, 9, 22),
+            1.3521,
+            103.8198,
+            at(2024, 9, 21, 22, 54),
+            at(2024, 9, 22, 11, 1),
+        );
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        let (latitude, longitude) = (69.6492, 18.9553);
+        assert_eq!(
+            sun_times(days_from_civil(2024, 6, 21), latitude, longitude),
+            None
+        );
+        assert_eq!(
+            sun_times(days_from_civil(2024, 12, 21), latitude, longitude),
+            None
+        );
+        // Around the equinox the sun rises and sets there like anywhere else.
+        assert!(sun_times(days_from_civil(2024, 3, 20), latitude, longitude).is_some());
+        // And in Antarctica the other way round.
+        assert_eq!(
+            sun_times(days_from_civil(2024, 6, 21), -77.85, 166.67),
+            None
+        );
+        assert_eq!(
+            sun_times(days_from_civil(2024, 12, 21), -77.85, 166.67),
+            None
+        );
+    }
+}